@@ -1,7 +1,8 @@
 use crate::server::{
-    http::Version,
+    http::{Headers, StatusCode, Version},
     response::{Response, ResponseContent},
 };
+use crate::templates::TemplateParsingError;
 
 #[derive(Debug)]
 pub enum OxidarError {
@@ -14,7 +15,7 @@ pub enum OxidarError {
 pub enum Error {
     Untyped(String),
     Io(std::io::Error),
-    Http404(Option<String>),
+    Http(StatusCode, Option<String>),
 }
 
 impl std::fmt::Display for Error {
@@ -22,12 +23,13 @@ impl std::fmt::Display for Error {
         match self {
             Error::Untyped(err) => write!(f, "(Untyped) {}", err),
             Error::Io(err) => write!(f, "(IO) {}", err),
-            Error::Http404(err) => write!(
+            Error::Http(status, err) => write!(
                 f,
-                "(404) {}",
+                "({}) {}",
+                status.as_u16(),
                 match err {
                     Some(msg) => msg,
-                    None => "Resource Not Found",
+                    None => status.reason(),
                 }
             ),
         }
@@ -35,11 +37,11 @@ impl std::fmt::Display for Error {
 }
 
 impl Error {
-    pub fn status_str(&self) -> &'static str {
+    pub fn status(&self) -> StatusCode {
         match self {
-            Error::Untyped(_) => "500 Server Error",
-            Error::Io(_) => "500 Server Error",
-            Error::Http404(_) => "404 Resource Not Found",
+            Error::Untyped(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Http(status, _) => *status,
         }
     }
 }
@@ -69,6 +71,13 @@ impl OxidarError {
         }
     }
 
+    pub fn nio<T>(r: Result<T, std::io::Error>) -> Result<T, OxidarError> {
+        match r {
+            Ok(r) => Ok(r),
+            Err(err) => Err(OxidarError::Normal(Error::Io(err))),
+        }
+    }
+
     pub fn abort_std(msg: String) -> Self {
         OxidarError::Fatal(Error::Untyped(msg))
     }
@@ -80,19 +89,71 @@ impl OxidarError {
             OxidarError::Normal(ref err) => err,
         };
 
-        let status = err.status_str();
+        let status = err.status();
         let msg = err.to_string();
 
         return Some(Response {
             version: Version::Http1_1,
             status,
-            content: ResponseContent::Html(format!("<h1 style='text-align: center'>{msg}</h1>")),
+            headers: Headers::new(),
+            content: ResponseContent::Html(format!(
+                "<h1 style='text-align: center'>{}</h1>",
+                escape_html(&msg)
+            )),
         });
     }
 
+    /// Gets the message out of a caught panic's payload.
+    pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+        match payload.downcast_ref::<&str>() {
+            Some(msg) => msg.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(msg) => msg.clone(),
+                None => "Unknown panic payload.".to_string(),
+            },
+        }
+    }
+
+    pub fn http(status: StatusCode, msg: Option<String>) -> OxidarError {
+        OxidarError::Normal(Error::Http(status, msg))
+    }
+
+    pub fn http_400(msg: Option<String>) -> OxidarError {
+        OxidarError::http(StatusCode::BAD_REQUEST, msg)
+    }
+
     pub fn http_404(msg: Option<String>) -> OxidarError {
-        OxidarError::Normal(Error::Http404(msg))
+        OxidarError::http(StatusCode::NOT_FOUND, msg)
+    }
+
+    pub fn http_413(msg: Option<String>) -> OxidarError {
+        OxidarError::http(StatusCode::CONTENT_TOO_LARGE, msg)
     }
 }
 
 impl std::error::Error for OxidarError {}
+
+/// Escapes the characters that could end text in an HTML element or in a
+/// quoted attribute. Error messages often hold parts of the request.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+impl From<TemplateParsingError> for OxidarError {
+    fn from(value: TemplateParsingError) -> Self {
+        OxidarError::Normal(Error::Untyped(value.to_string()))
+    }
+}
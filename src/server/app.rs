@@ -1,13 +1,27 @@
+use std::{any::Any, collections::HashMap};
+#[cfg(feature = "async")]
+use std::{future::Future, pin::Pin};
+
 use crate::errors::OxidarError;
 
 use super::{
-    http::Version,
+    http::{Method, StatusCode},
+    middleware::Middleware,
+    path::PathPattern,
     request::Request,
-    response::{Response, ResponseContent},
+    response::{IntoResponse, Response, ResponseContent},
+    state::State,
+    websocket::WebSocket,
     Oxidar,
 };
 
-pub struct AppReg(pub String, pub App);
+pub struct AppReg {
+    pub prefix: String,
+    pub namespace: Option<String>,
+    pub app: App,
+    pub middleware: Vec<Box<dyn Middleware>>,
+}
+
 impl AppReg {
     pub fn p(path: &str, app: App) -> AppReg {
         let mut path = path.to_string();
@@ -20,35 +34,533 @@ impl AppReg {
             path.push('/');
         }
 
-        AppReg(path.to_string(), app)
+        AppReg {
+            prefix: path,
+            namespace: None,
+            app,
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Sets the namespace used to reverse the app's named views, as in
+    /// `"namespace:view_name"`.
+    pub fn namespace(mut self, namespace: &str) -> AppReg {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Adds middleware that runs around the app's views, inside any global
+    /// middleware.
+    pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> AppReg {
+        self.middleware.push(Box::new(middleware));
+        self
     }
 }
 
-pub struct ViewReg(pub String, pub fn(&App, &Request) -> ResponseContent);
+pub type SyncView = Box<dyn Fn(&App, &Request) -> Result<Response, OxidarError> + Send + Sync>;
+
+/// A boxed future that can be sent between threads.
+#[cfg(feature = "async")]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[cfg(feature = "async")]
+pub type AsyncView = Box<
+    dyn for<'a> Fn(&'a App, &'a Request) -> BoxFuture<'a, Result<Response, OxidarError>>
+        + Send
+        + Sync,
+>;
+
+pub enum View {
+    Sync(SyncView),
+    #[cfg(feature = "async")]
+    Async(AsyncView),
+}
+
+/// Gives a closure the higher ranked signature an `AsyncView` needs.
+#[cfg(feature = "async")]
+fn async_view<F>(view: F) -> AsyncView
+where
+    F: for<'a> Fn(&'a App, &'a Request) -> BoxFuture<'a, Result<Response, OxidarError>>
+        + Send
+        + Sync
+        + 'static,
+{
+    Box::new(view)
+}
+
+pub struct ViewReg {
+    pub path: PathPattern,
+    pub name: Option<String>,
+    /// The methods the view answers, or `None` for every method.
+    pub methods: Option<Vec<Method>>,
+    pub view: View,
+}
+
 impl ViewReg {
-    pub fn p(path: &str, view: fn(&App, &Request) -> ResponseContent) -> ViewReg {
-        ViewReg(path.to_string(), view)
+    /// Registers a view under `path`. The path may contain typed parameters
+    /// such as `/posts/<int:id>/<slug:title>`. The view may be a function or
+    /// a closure, and may return anything that implements `IntoResponse`.
+    ///
+    /// Panics if the path is not a valid pattern.
+    pub fn p<F, R>(path: &str, view: F) -> ViewReg
+    where
+        F: Fn(&App, &Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        match PathPattern::parse(path) {
+            Ok(path) => ViewReg {
+                path,
+                name: None,
+                methods: None,
+                view: View::Sync(Box::new(move |app, request| {
+                    view(app, request).into_response()
+                })),
+            },
+            Err(err) => panic!("Invalid view path: {err}"),
+        }
+    }
+
+    /// Registers an async view under `path`. The view returns a boxed future,
+    /// usually written as `|app, request| Box::pin(async move { ... })` or
+    /// `|app, request| Box::pin(my_view(app, request))` for an `async fn`.
+    ///
+    /// Async views are awaited on the runtime when the server is started with
+    /// `Oxidar::run_async`, and blocked on when it is started with `run`.
+    ///
+    /// Panics if the path is not a valid pattern.
+    #[cfg(feature = "async")]
+    pub fn a<F, R>(path: &str, view: F) -> ViewReg
+    where
+        F: for<'a> Fn(&'a App, &'a Request) -> BoxFuture<'a, R> + Send + Sync + 'static,
+        R: IntoResponse + 'static,
+    {
+        match PathPattern::parse(path) {
+            Ok(path) => ViewReg {
+                path,
+                name: None,
+                methods: None,
+                view: View::Async(async_view(move |app, request| {
+                    let future = view(app, request);
+                    Box::pin(async move { future.await.into_response() })
+                })),
+            },
+            Err(err) => panic!("Invalid view path: {err}"),
+        }
+    }
+
+    /// Registers a view that only answers `GET`, and so `HEAD`, requests.
+    pub fn get<F, R>(path: &str, view: F) -> ViewReg
+    where
+        F: Fn(&App, &Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        ViewReg::p(path, view).methods(&[Method::GET])
+    }
+
+    /// Registers a view that only answers `POST` requests.
+    pub fn post<F, R>(path: &str, view: F) -> ViewReg
+    where
+        F: Fn(&App, &Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        ViewReg::p(path, view).methods(&[Method::POST])
+    }
+
+    /// Registers a view that only answers `PUT` requests.
+    pub fn put<F, R>(path: &str, view: F) -> ViewReg
+    where
+        F: Fn(&App, &Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        ViewReg::p(path, view).methods(&[Method::PUT])
+    }
+
+    /// Registers a view that only answers `PATCH` requests.
+    pub fn patch<F, R>(path: &str, view: F) -> ViewReg
+    where
+        F: Fn(&App, &Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        ViewReg::p(path, view).methods(&[Method::PATCH])
+    }
+
+    /// Registers a view that only answers `DELETE` requests.
+    pub fn delete<F, R>(path: &str, view: F) -> ViewReg
+    where
+        F: Fn(&App, &Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        ViewReg::p(path, view).methods(&[Method::DELETE])
     }
+
+    /// Limits the view to the given methods. A view that answers `GET` also
+    /// answers `HEAD`, and `OPTIONS` is answered automatically unless it is
+    /// listed here. Other methods get a 405 response.
+    pub fn methods(mut self, methods: &[Method]) -> ViewReg {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    fn allows(&self, method: Method) -> bool {
+        match self.methods {
+            Some(ref methods) => methods.contains(&method),
+            None => true,
+        }
+    }
+
+    /// Names the view so its url can be rebuilt with `Oxidar::reverse`.
+    pub fn name(mut self, name: &str) -> ViewReg {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+pub type WsHandler =
+    Box<dyn Fn(&App, &Request, &mut WebSocket) -> Result<(), OxidarError> + Send + Sync>;
+
+/// The largest message a WebSocket accepts by default, 16 MiB.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+pub struct WsReg {
+    pub path: PathPattern,
+    pub name: Option<String>,
+    /// The subprotocols the handler speaks, most preferred first.
+    pub protocols: Vec<String>,
+    pub max_message_size: usize,
+    pub handler: WsHandler,
+}
+
+impl WsReg {
+    /// Registers a WebSocket handler under `path`, which may contain typed
+    /// parameters like a view's path. Once the upgrade is accepted the handler
+    /// holds the connection for as long as it runs, and the socket is closed
+    /// when it returns.
+    ///
+    /// ```ignore
+    /// WsReg::p("/echo", |_app, _request, socket| loop {
+    ///     match socket.recv()? {
+    ///         Message::Text(text) => socket.send_text(&text)?,
+    ///         Message::Close(_) => return Ok(()),
+    ///         _ => {}
+    ///     }
+    /// })
+    /// ```
+    ///
+    /// Panics if the path is not a valid pattern.
+    pub fn p<F>(path: &str, handler: F) -> WsReg
+    where
+        F: Fn(&App, &Request, &mut WebSocket) -> Result<(), OxidarError> + Send + Sync + 'static,
+    {
+        match PathPattern::parse(path) {
+            Ok(path) => WsReg {
+                path,
+                name: None,
+                protocols: Vec::new(),
+                max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                handler: Box::new(handler),
+            },
+            Err(err) => panic!("Invalid WebSocket path: {err}"),
+        }
+    }
+
+    /// Names the socket so its url can be rebuilt with `Oxidar::reverse`.
+    pub fn name(mut self, name: &str) -> WsReg {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Sets the subprotocols offered through `Sec-WebSocket-Protocol`. The
+    /// first one the client also asked for is used.
+    pub fn protocols(mut self, protocols: &[&str]) -> WsReg {
+        self.protocols = protocols.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Sets the largest message, after fragments are joined, that the socket
+    /// accepts. Larger messages close the socket with `MESSAGE_TOO_BIG`.
+    pub fn max_message_size(mut self, size: usize) -> WsReg {
+        self.max_message_size = size;
+        self
+    }
+}
+
+enum Route<'a> {
+    View(&'a View),
+    Response(Result<Response, OxidarError>),
 }
 
 pub struct App {
     urls: Vec<ViewReg>,
+    websockets: Vec<WsReg>,
+    state: State,
 }
 
 impl App {
     pub fn new(urls: Vec<ViewReg>) -> App {
-        App { urls }
+        App {
+            urls,
+            websockets: Vec::new(),
+            state: State::new(),
+        }
     }
 
+    /// Stores a value that the app's views can get with `App::state`.
+    pub fn with_state<T: Any + Send + Sync>(mut self, value: T) -> App {
+        self.state.insert(value);
+        self
+    }
+
+    /// Gets the app state of type `T`. For state shared by every app see
+    /// `Request::state`.
+    pub fn state<T: Any + Send + Sync>(&self) -> Result<&T, OxidarError> {
+        self.state.get()
+    }
+
+    pub fn urls(&self) -> &[ViewReg] {
+        &self.urls
+    }
+
+    /// Adds WebSocket handlers. Upgrade requests are matched against these
+    /// before the views.
+    pub fn websockets(mut self, websockets: Vec<WsReg>) -> App {
+        self.websockets.extend(websockets);
+        self
+    }
+
+    pub fn sockets(&self) -> &[WsReg] {
+        &self.websockets
+    }
+
+    /// Finds the WebSocket handler for `path` and sets its path parameters.
+    pub(crate) fn websocket(&self, path: &str, request: &mut Request) -> Option<&WsReg> {
+        self.websockets.iter().find_map(|socket| {
+            let params = socket.path.matches(path)?;
+            request.params = params;
+            Some(socket)
+        })
+    }
+
+    /// Dispatches the request to the first view whose path matches `path`,
+    /// the part of the request path left over once the app prefix is removed.
     pub(crate) fn respond(
         &self,
-        oxidar: &Oxidar,
-        request: Request,
+        _oxidar: &Oxidar,
+        path: &str,
+        mut request: Request,
+    ) -> Result<Response, OxidarError> {
+        match self.route(path, &mut request) {
+            Route::View(View::Sync(view)) => view(self, &request),
+            #[cfg(feature = "async")]
+            Route::View(View::Async(view)) => super::async_server::block_on(view(self, &request)),
+            Route::Response(response) => response,
+        }
+    }
+
+    /// Dispatches the request like `respond`, awaiting async views. Blocking
+    /// views are run with `block_in_place` so they do not stall the runtime.
+    #[cfg(feature = "async")]
+    pub(crate) async fn respond_async(
+        &self,
+        _oxidar: &Oxidar,
+        path: &str,
+        mut request: Request,
     ) -> Result<Response, OxidarError> {
-        Ok(Response {
-            version: Version::Http1_1,
-            status: "200 OK",
-            content: ResponseContent::Html(format!("WE HAVE SOME SUCCESS")),
+        match self.route(path, &mut request) {
+            Route::View(View::Sync(view)) => tokio::task::block_in_place(|| view(self, &request)),
+            Route::View(View::Async(view)) => view(self, &request).await,
+            Route::Response(response) => response,
+        }
+    }
+
+    /// Finds the view for the request and sets its path parameters, or works
+    /// out the response when no view answers the method.
+    fn route(&self, path: &str, request: &mut Request) -> Route<'_> {
+        let mut matched = Vec::new();
+
+        for view in &self.urls {
+            if let Some(params) = view.path.matches(path) {
+                if view.allows(request.method) {
+                    request.params = params;
+                    return Route::View(&view.view);
+                }
+
+                matched.push((view, params));
+            }
+        }
+
+        if matched.is_empty() {
+            return Route::Response(Err(OxidarError::http_404(Some(format!(
+                "No view found for \"{}\".",
+                request.path
+            )))));
+        }
+
+        if request.method == Method::HEAD {
+            if let Some((view, params)) = matched.iter().find(|(v, _)| v.allows(Method::GET)) {
+                request.params = params.clone();
+                return Route::View(&view.view);
+            }
+        }
+
+        let allow = Self::allowed_methods(&matched)
+            .iter()
+            .map(|method| method.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        if request.method == Method::OPTIONS {
+            return Route::Response(Ok(
+                Response::new(ResponseContent::Empty).header("Allow", &allow)
+            ));
+        }
+
+        let err = OxidarError::http(
+            StatusCode::METHOD_NOT_ALLOWED,
+            Some(format!(
+                "{} is not allowed for \"{}\".",
+                request.method, request.path
+            )),
+        );
+
+        Route::Response(match err.to_response() {
+            Some(response) => Ok(response.header("Allow", &allow)),
+            None => Err(err),
         })
     }
+
+    fn allowed_methods(matched: &[(&ViewReg, HashMap<String, String>)]) -> Vec<Method> {
+        let mut allowed = Vec::new();
+        for (view, _) in matched {
+            if let Some(ref methods) = view.methods {
+                allowed.extend(methods.iter().copied());
+            }
+        }
+
+        if allowed.contains(&Method::GET) {
+            allowed.push(Method::HEAD);
+        }
+        allowed.push(Method::OPTIONS);
+
+        let mut unique = Vec::new();
+        for method in allowed {
+            if !unique.contains(&method) {
+                unique.push(method);
+            }
+        }
+
+        unique
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::server::request::test_request;
+
+    fn html(response: Result<Response, OxidarError>) -> (StatusCode, String) {
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => err.to_response().unwrap(),
+        };
+
+        match response.content {
+            ResponseContent::Html(html) => (response.status, html),
+            _ => panic!("expected an HTML response"),
+        }
+    }
+
+    fn route(app: &App, method: Method, uri: &str) -> Result<Response, OxidarError> {
+        let mut request = test_request(method, uri, &[], b"");
+        let path = request.path.clone();
+
+        match app.route(&path, &mut request) {
+            Route::View(View::Sync(view)) => view(app, &request),
+            #[cfg(feature = "async")]
+            Route::View(View::Async(_)) => panic!("expected a sync view"),
+            Route::Response(response) => response,
+        }
+    }
+
+    #[test]
+    fn escapes_the_path_in_error_pages() {
+        let app = App::new(vec![ViewReg::get("/", |_, _| "index")]);
+
+        let (status, html) = html(route(
+            &app,
+            Method::GET,
+            "/%3Cscript%3Ealert('x')%3C/script%3E",
+        ));
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(&#x27;x&#x27;)&lt;/script&gt;"));
+    }
+
+    #[test]
+    fn routes_by_method() {
+        let app = App::new(vec![
+            ViewReg::get("/items", |_, _| "list"),
+            ViewReg::post("/items", |_, _| "create"),
+            ViewReg::p("/any", |_, _| "any"),
+        ]);
+
+        assert_eq!(html(route(&app, Method::GET, "/items")).1, "list");
+        assert_eq!(html(route(&app, Method::POST, "/items")).1, "create");
+        assert_eq!(html(route(&app, Method::HEAD, "/items")).1, "list");
+        assert_eq!(html(route(&app, Method::DELETE, "/any")).1, "any");
+
+        let response = route(&app, Method::OPTIONS, "/items").unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, POST, HEAD, OPTIONS")
+        );
+
+        let response = route(&app, Method::DELETE, "/items").unwrap();
+        assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, POST, HEAD, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn escapes_the_path_in_405_pages() {
+        let app = App::new(vec![ViewReg::get("/<path:rest>", |_, _| "page")]);
+
+        let (status, html) = html(route(&app, Method::PUT, "/%3Cb%3E"));
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(html.contains("PUT is not allowed for &quot;/&lt;b&gt;&quot;."));
+    }
+
+    #[test]
+    fn views_can_capture_and_share_state() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+
+        let app = App::new(vec![
+            ViewReg::get("/count", move |_, _| {
+                format!("{}", counted.fetch_add(1, Ordering::SeqCst) + 1)
+            }),
+            ViewReg::get("/name", |app, _| app.state::<String>().cloned()),
+            ViewReg::get("/missing", |app, _| {
+                app.state::<u8>().map(|n| n.to_string())
+            }),
+        ])
+        .with_state("oxidar".to_string());
+
+        assert_eq!(html(route(&app, Method::GET, "/count")).1, "1");
+        assert_eq!(html(route(&app, Method::GET, "/count")).1, "2");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        assert_eq!(html(route(&app, Method::GET, "/name")).1, "oxidar");
+        assert_eq!(
+            html(route(&app, Method::GET, "/missing")).0,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
 }
@@ -0,0 +1,483 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{
+        self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::{TcpListener, TcpStream},
+    runtime::{Builder, Handle, Runtime, RuntimeFlavor},
+    task::JoinSet,
+    time::timeout,
+};
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(feature = "http2")]
+use super::http2::sniff_preface;
+use super::{
+    http::Method,
+    parser::{io_error, timed_out, ParsedRequest, ParserLimits, RequestParser},
+    request::Request,
+    response::Response,
+    shutdown::install_signal_handlers,
+    stream::Acceptor,
+    websocket::{is_upgrade, Upgrade},
+    Oxidar, ACCEPT_POLL_INTERVAL, IDLE_POLL_INTERVAL,
+};
+use crate::errors::OxidarError;
+
+/// Runs an async view to completion from a blocking worker thread, on a
+/// runtime shared by every worker. Called from blocking code on the server's
+/// own runtime, such as middleware, it runs on that runtime instead.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+    if let Ok(handle) = Handle::try_current() {
+        return tokio::task::block_in_place(|| handle.block_on(future));
+    }
+
+    RUNTIME
+        .get_or_init(|| {
+            Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()
+                .expect("Could not start the runtime for async views.")
+        })
+        .block_on(future)
+}
+
+/// Catches a panic raised while polling the inner future.
+struct CatchUnwind<'a, T>(Pin<Box<dyn Future<Output = T> + Send + 'a>>);
+
+impl<T> Future for CatchUnwind<'_, T> {
+    type Output = std::thread::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// Adapts an async connection to blocking reads and writes for code running
+/// inside `block_in_place`. Reads time out regularly, as they do on blocking
+/// connections, so shutdowns are noticed.
+struct BlockingIo<'a, R, W> {
+    reader: &'a mut BufReader<R>,
+    writer: &'a mut W,
+}
+
+impl<R: AsyncRead + Unpin, W> std::io::Read for BlockingIo<'_, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = timeout(IDLE_POLL_INTERVAL, self.reader.read(buf));
+        match Handle::current().block_on(read) {
+            Ok(result) => result,
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+impl<R, W: AsyncWrite + Unpin> std::io::Write for BlockingIo<'_, R, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Handle::current().block_on(self.writer.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Handle::current().block_on(self.writer.flush())
+    }
+}
+
+impl Oxidar {
+    /// Serves requests on a multi-threaded tokio runtime with one worker
+    /// thread per configured thread, instead of the blocking thread pool.
+    /// Shutdown works as it does for `run`.
+    ///
+    /// Blocking views, and every request that passes through middleware,
+    /// hold a runtime worker until they are done. See `Middleware`.
+    ///
+    /// With the `http2` feature this is also the only way HTTP/2 is served,
+    /// as `run` speaks HTTP/1.1 alone.
+    pub fn run_async(self) -> Result<(), OxidarError> {
+        let runtime = OxidarError::fio(
+            Builder::new_multi_thread()
+                .worker_threads(self.threads.max(1))
+                .enable_all()
+                .build(),
+        )?;
+
+        runtime.block_on(self.serve())
+    }
+
+    /// Serves requests on the tokio runtime the future is polled on, which
+    /// must be a multi-threaded runtime. Each connection is handled by its own
+    /// task, so the queue depth limit of `run` does not apply.
+    ///
+    /// Blocking views and middleware run with `block_in_place`, which a
+    /// current thread runtime does not allow, so on one this returns an
+    /// error before serving anything.
+    pub async fn serve(self) -> Result<(), OxidarError> {
+        if Handle::current().runtime_flavor() == RuntimeFlavor::CurrentThread {
+            return Err(OxidarError::abort_std(
+                "Oxidar::serve needs a multi-threaded tokio runtime.".to_string(),
+            ));
+        }
+
+        let oxidar = Arc::new(self);
+        let listener = OxidarError::fio(TcpListener::bind(oxidar.socket_addr).await)?;
+        let acceptor = Acceptor::new(&oxidar, cfg!(feature = "http2"))?;
+
+        if oxidar.handle_signals {
+            install_signal_handlers(&oxidar.shutdown);
+        }
+
+        oxidar.log(format!(
+            "Started async server. Listening on {}",
+            oxidar.socket_addr
+        ));
+
+        let mut connections = JoinSet::new();
+        while !oxidar.shutdown.is_shutting_down() {
+            while connections.try_join_next().is_some() {}
+
+            let stream = match timeout(ACCEPT_POLL_INTERVAL, listener.accept()).await {
+                Ok(Ok((stream, _))) => stream,
+                Ok(Err(err)) => {
+                    oxidar.loge(format!("Could not accept a connection: {err}"));
+                    continue;
+                }
+                Err(_) => continue,
+            };
+
+            let acceptor = acceptor.clone();
+            let oxidar = oxidar.clone();
+            connections.spawn(async move {
+                if let Err(err) = oxidar.clone().accept_async(&acceptor, stream).await {
+                    oxidar.log_connection_error(err);
+                }
+            });
+        }
+
+        oxidar.log("Shutting down. No longer accepting connections.".to_string());
+        drop(listener);
+
+        let drained = timeout(oxidar.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            oxidar.logw(format!(
+                "{} connections did not finish before the shutdown timeout.",
+                connections.len()
+            ));
+            connections.abort_all();
+        }
+
+        oxidar.log("Shutdown complete.".to_string());
+        Ok(())
+    }
+
+    /// Starts a TLS session on the socket if TLS is configured, then serves
+    /// the connection. HTTP/2 is used when it is negotiated through ALPN or,
+    /// without TLS, when the client starts with the HTTP/2 preface. Requests
+    /// that ask to upgrade to `h2c` are answered over HTTP/1.1.
+    async fn accept_async(
+        self: Arc<Self>,
+        acceptor: &Acceptor,
+        stream: TcpStream,
+    ) -> Result<(), OxidarError> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = acceptor.tls_config() {
+            let handshake = TlsAcceptor::from(config).accept(stream);
+            let stream = match timeout(self.keep_alive_timeout, handshake).await {
+                Ok(stream) => stream,
+                Err(elapsed) => Err(elapsed.into()),
+            }
+            .map_err(|err| OxidarError::Abortion(crate::errors::Error::Io(err)))?;
+
+            #[cfg(feature = "http2")]
+            if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                return self.serve_h2(stream).await;
+            }
+
+            return self.handle_connection_async(stream).await;
+        }
+
+        #[cfg(not(feature = "tls"))]
+        let _ = acceptor;
+
+        #[cfg(feature = "http2")]
+        {
+            let (prior_knowledge, stream) = sniff_preface(stream, self.keep_alive_timeout).await?;
+
+            match prior_knowledge {
+                true => self.serve_h2(stream).await,
+                false => self.handle_connection_async(stream).await,
+            }
+        }
+
+        #[cfg(not(feature = "http2"))]
+        self.handle_connection_async(stream).await
+    }
+
+    /// The async counterpart of `handel_connection`.
+    async fn handle_connection_async<S>(self: Arc<Self>, stream: S) -> Result<(), OxidarError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let (read, mut write) = io::split(stream);
+        let mut reader = BufReader::new(read);
+        let mut served = 0;
+
+        loop {
+            if !self
+                .wait_for_request_async(&mut reader, served == 0)
+                .await?
+            {
+                return Ok(());
+            }
+
+            let parsed = read_request(&mut reader, &self.limits, self.keep_alive_timeout).await;
+            let request = parsed.and_then(|parsed| {
+                parsed
+                    .map(|parsed| {
+                        Request::new(parsed, self.urls.clone(), self.state.clone(), &self.limits)
+                    })
+                    .transpose()
+            });
+
+            let request = match request {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    if let Some(response) = err.to_response() {
+                        let response = response.header("Connection", "close");
+                        OxidarError::aio(write.write_all(&response.build_response()).await)?;
+                        OxidarError::aio(write.flush().await)?;
+                    }
+
+                    return Err(err);
+                }
+            };
+
+            served += 1;
+            let keep_alive = served < self.max_keep_alive_requests
+                && !self.shutdown.is_shutting_down()
+                && Self::wants_keep_alive(&request);
+
+            let head = request.method == Method::HEAD;
+            let version = request.version;
+
+            // Middleware and WebSocket handlers are blocking, so they run the
+            // way blocking views do, with the connection read and written
+            // through the runtime.
+            let (response, err) = match is_upgrade(&request) {
+                true => match tokio::task::block_in_place(|| self.upgrade_websocket(request)) {
+                    Upgrade::Accept(app, socket, request, headers) => {
+                        return tokio::task::block_in_place(|| {
+                            let stream = BlockingIo {
+                                reader: &mut reader,
+                                writer: &mut write,
+                            };
+                            self.serve_websocket(app, socket, *request, headers, Box::new(stream))
+                        });
+                    }
+                    Upgrade::Respond(result) => Self::settle(result)?,
+                },
+                false => self.dispatch_async(request).await?,
+            };
+
+            let (raw, chunks, keep_alive) =
+                self.finish_response(response, head, version, keep_alive);
+            OxidarError::aio(write.write_all(&raw).await)?;
+            OxidarError::aio(write.flush().await)?;
+
+            // Producing a chunk may block, as it does for event streams
+            // waiting on their next event.
+            if let Some(mut chunks) = chunks {
+                while let Some(chunk) = tokio::task::block_in_place(|| chunks.next()) {
+                    OxidarError::aio(write.write_all(&Self::stream_chunk(chunk)?).await)?;
+                    OxidarError::aio(write.flush().await)?;
+                }
+            }
+
+            if let Some(OxidarError::Normal(error)) = err {
+                self.loge(error);
+            }
+
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Gets the response to a request from its app, as `route_to_app` does.
+    pub(crate) async fn dispatch_async(
+        &self,
+        request: Request,
+    ) -> Result<(Response, Option<OxidarError>), OxidarError> {
+        self.log(format!("Processing: {} {}", request.method, request.uri));
+
+        // Middleware is blocking, so when there is any the whole chain runs
+        // the way blocking views do.
+        if self.has_middleware(&request.path) {
+            let (method, uri) = (request.method, request.uri.clone());
+            let result = tokio::task::block_in_place(|| {
+                panic::catch_unwind(AssertUnwindSafe(|| self.respond(request)))
+            });
+
+            return match result {
+                Ok(result) => Self::settle(result),
+                Err(payload) => Self::settle(Err(self.view_panicked(method, &uri, &*payload))),
+            };
+        }
+
+        match self.find_app(&request.path) {
+            Some((app, path)) => {
+                let (method, uri) = (request.method, request.uri.clone());
+                let result = match CatchUnwind(Box::pin(
+                    app.app.respond_async(self, &path, request),
+                ))
+                .await
+                {
+                    Ok(result) => result,
+                    Err(payload) => Err(self.view_panicked(method, &uri, &*payload)),
+                };
+
+                Self::settle(result)
+            }
+            None => Ok((Self::no_app()?, None)),
+        }
+    }
+
+    /// The async counterpart of `wait_for_request`.
+    async fn wait_for_request_async<R: AsyncRead + Unpin>(
+        &self,
+        reader: &mut BufReader<R>,
+        first: bool,
+    ) -> Result<bool, OxidarError> {
+        if !reader.buffer().is_empty() {
+            return Ok(true);
+        }
+
+        let started = Instant::now();
+
+        loop {
+            if !first && self.shutdown.is_shutting_down() {
+                return Ok(false);
+            }
+
+            let remaining = self.keep_alive_timeout.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+
+            match timeout(remaining.min(IDLE_POLL_INTERVAL), reader.fill_buf()).await {
+                Ok(Ok(buf)) => return Ok(!buf.is_empty()),
+                Ok(Err(err)) => return OxidarError::fio(Err(err)),
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// The async counterpart of `parse_request`, feeding the same parser. Each
+/// read must arrive within `read_timeout`, as the blocking server's socket
+/// read timeout works, so slow but steady uploads are not cut off.
+async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    limits: &ParserLimits,
+    read_timeout: Duration,
+) -> Result<Option<ParsedRequest>, OxidarError> {
+    let mut parser = RequestParser::new(limits);
+
+    loop {
+        let buf = match timeout(read_timeout, reader.fill_buf()).await {
+            Ok(buf) => buf.map_err(io_error)?,
+            Err(_) => return Err(timed_out()),
+        };
+
+        if buf.is_empty() {
+            return parser.finish();
+        }
+
+        let (used, request) = parser.feed(buf)?;
+        reader.consume(used);
+
+        if request.is_some() {
+            return Ok(request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        thread,
+    };
+
+    use super::*;
+    use crate::server::{
+        app::{App, AppReg, ViewReg},
+        connect, free_addr, LogStyle,
+    };
+
+    fn oxidar(addr: &'static str) -> Oxidar {
+        let app = App::new(vec![
+            ViewReg::get("/sync", |_, _| "sync"),
+            ViewReg::a("/async", |_, _| {
+                Box::pin(async {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    "async"
+                })
+            }),
+        ]);
+        Oxidar::new(
+            vec![AppReg::p("/", app)],
+            addr,
+            2,
+            LogStyle::Terminal,
+            false,
+        )
+    }
+
+    #[test]
+    fn serves_sync_and_async_views() {
+        let addr = free_addr();
+        let oxidar = oxidar(addr);
+        let shutdown = oxidar.shutdown_handle();
+        let server = thread::spawn(move || oxidar.run_async());
+
+        for (path, body) in [("/sync", "sync"), ("/async", "async")] {
+            let mut stream = connect(addr);
+            let request = format!("GET {path} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+            stream.write_all(request.as_bytes()).unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+            assert!(response.ends_with(body), "{response}");
+        }
+
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn refuses_current_thread_runtimes() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        assert!(runtime.block_on(oxidar(free_addr()).serve()).is_err());
+    }
+}
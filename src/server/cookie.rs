@@ -0,0 +1,454 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{date::format_http_date, encoding::percent_decode};
+use crate::errors::OxidarError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The shortest secret accepted by `Key::from_secret`.
+const MIN_SECRET_LEN: usize = 32;
+
+/// The length of an AES-GCM nonce.
+const NONCE_LEN: usize = 12;
+
+/// Controls whether a cookie is sent with requests started by other sites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Sent with every request. Browsers only accept this on secure cookies,
+    /// so it also marks the cookie `Secure`.
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+/// A cookie to set with `Response::cookie`.
+///
+/// ```ignore
+/// Cookie::new("theme", "dark")
+///     .path("/")
+///     .max_age(Duration::from_secs(60 * 60 * 24 * 365))
+///     .same_site(SameSite::Lax)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<Duration>,
+    pub expires: Option<SystemTime>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// Creates a session cookie, which the browser drops when it is closed
+    /// unless `max_age` or `expires` is set.
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    pub fn path(mut self, path: &str) -> Cookie {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Cookie {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> Cookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Only sends the cookie over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    /// Hides the cookie from JavaScript.
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Signs the value so it can be read back with `Request::signed_cookie`,
+    /// which rejects it if it has been changed. The value can still be read
+    /// by the client.
+    pub fn signed(mut self, key: &Key) -> Cookie {
+        let mac = key.sign(&self.name, &self.value);
+        self.value = format!("{mac}.{}", self.value);
+        self
+    }
+
+    /// Encrypts the value so it can be read back with
+    /// `Request::private_cookie`. The client can neither read nor change it.
+    pub fn encrypted(mut self, key: &Key) -> Cookie {
+        self.value = key.encrypt(&self.name, &self.value);
+        self
+    }
+
+    /// Builds the value of the `Set-Cookie` header. The value is
+    /// percent-encoded where it has characters a cookie can not hold, and
+    /// decoded again by `Request::cookies`.
+    pub(crate) fn to_header(&self) -> String {
+        let clean = |s: &str| s.replace([';', '\r', '\n'], "");
+        let mut header = format!("{}={}", clean(&self.name), encode_value(&self.value));
+
+        if let Some(ref path) = self.path {
+            header.push_str(&format!("; Path={}", clean(path)));
+        }
+
+        if let Some(ref domain) = self.domain {
+            header.push_str(&format!("; Domain={}", clean(domain)));
+        }
+
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+
+        if let Some(expires) = self.expires {
+            header.push_str(&format!("; Expires={}", format_http_date(expires)));
+        }
+
+        if self.secure || self.same_site == Some(SameSite::None) {
+            header.push_str("; Secure");
+        }
+
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+
+        if let Some(same_site) = self.same_site {
+            header.push_str(&format!("; SameSite={same_site}"));
+        }
+
+        header
+    }
+
+    /// A cookie that tells the browser to drop this one. It keeps the path
+    /// and domain, as the browser needs them to find the cookie.
+    pub(crate) fn removal(&self) -> Cookie {
+        Cookie {
+            value: String::new(),
+            max_age: Some(Duration::ZERO),
+            expires: Some(UNIX_EPOCH),
+            ..self.clone()
+        }
+    }
+}
+
+/// Parses `Cookie` headers into a map of names to values. Where a name is
+/// sent more than once, the first value is kept, as browsers send the cookie
+/// with the most specific path first.
+pub(crate) fn parse_cookies<'a, I: IntoIterator<Item = &'a str>>(
+    headers: I,
+) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+
+    for header in headers {
+        for pair in header.split(';') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+
+            let value = value.trim().trim_matches('"');
+            cookies
+                .entry(name.to_string())
+                .or_insert_with(|| percent_decode(value));
+        }
+    }
+
+    cookies
+}
+
+/// Percent-encodes the bytes a cookie value can not hold, along with `%`
+/// itself so that decoding gives back the original value.
+fn encode_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'!' | b'#'..=b'$' | b'&'..=b'+' | b'-'..=b':' | b'<'..=b'[' | b']'..=b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+/// The secret used to sign and encrypt cookies, set with
+/// `Oxidar::secret_key` and available to views through `Request::key`.
+///
+/// Separate keys for signing and encryption are derived from the secret, so
+/// it can be any random string of at least 32 bytes. Changing the secret
+/// invalidates every signed and encrypted cookie already handed out.
+#[derive(Clone)]
+pub struct Key {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl Key {
+    pub fn from_secret(secret: &[u8]) -> Result<Key, OxidarError> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(OxidarError::abort_std(format!(
+                "The secret key must be at least {MIN_SECRET_LEN} bytes long."
+            )));
+        }
+
+        let derive = |label: &[u8]| {
+            let mut mac =
+                <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC takes keys of any size.");
+            mac.update(label);
+            <[u8; 32]>::from(mac.finalize().into_bytes())
+        };
+
+        Ok(Key {
+            signing: derive(b"oxidar cookie signing"),
+            encryption: derive(b"oxidar cookie encryption"),
+        })
+    }
+
+    /// The signature of a cookie value, which also covers the name so a
+    /// signed value can not be moved to another cookie.
+    fn sign(&self, name: &str, value: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(name, value).finalize().into_bytes())
+    }
+
+    fn mac(&self, name: &str, value: &str) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.signing)
+            .expect("HMAC takes keys of any size.");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    /// Checks a signed value, returning the value without its signature.
+    pub(crate) fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (signature, value) = signed.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+        self.mac(name, value).verify_slice(&signature).ok()?;
+        Some(value.to_string())
+    }
+
+    /// Encrypts a cookie value with AES-256-GCM under a random nonce. The name
+    /// is authenticated along with it.
+    fn encrypt(&self, name: &str, value: &str) -> String {
+        let cipher = Aes256Gcm::new(&self.encryption.into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: name.as_bytes(),
+        };
+
+        let sealed = cipher
+            .encrypt(&nonce, payload)
+            .expect("Encrypting a cookie can not fail.");
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&sealed);
+        URL_SAFE_NO_PAD.encode(data)
+    }
+
+    /// Decrypts a value made by `encrypt`, failing if it was changed or made
+    /// for another cookie.
+    pub(crate) fn decrypt(&self, name: &str, encrypted: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(&self.encryption.into());
+        let payload = Payload {
+            msg: sealed,
+            aad: name.as_bytes(),
+        };
+
+        let value = cipher.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+        String::from_utf8(value).ok()
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        Key::from_secret(b"a secret that is at least 32 bytes long").unwrap()
+    }
+
+    #[test]
+    fn builds_set_cookie_headers() {
+        let cookie = Cookie::new("theme", "dark")
+            .path("/")
+            .domain("example.com")
+            .max_age(Duration::from_secs(60))
+            .expires(UNIX_EPOCH + Duration::from_secs(784111777))
+            .http_only(true)
+            .same_site(SameSite::Lax);
+
+        assert_eq!(
+            cookie.to_header(),
+            "theme=dark; Path=/; Domain=example.com; Max-Age=60; \
+             Expires=Sun, 06 Nov 1994 08:49:37 GMT; HttpOnly; SameSite=Lax"
+        );
+
+        let cookie = Cookie::new("a", "b").same_site(SameSite::None);
+        assert_eq!(cookie.to_header(), "a=b; Secure; SameSite=None");
+
+        let removal = Cookie::new("a", "b").path("/app").removal();
+        assert_eq!(
+            removal.to_header(),
+            "a=; Path=/app; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn keeps_headers_from_being_split() {
+        let cookie = Cookie::new("a;b\r\n", "x; Secure\r\nSet-Cookie: c=d").path("/;\r\n");
+        let header = cookie.to_header();
+
+        assert!(!header.contains(['\r', '\n']));
+        assert!(header.starts_with("ab=x%3B%20Secure%0D%0ASet-Cookie:%20c=d; Path=/"));
+    }
+
+    #[test]
+    fn encoded_values_parse_back() {
+        let value = "a b;c,d\"e%f=g\\é";
+        let header = Cookie::new("v", value).to_header();
+        let cookies = parse_cookies([header.as_str()]);
+        assert_eq!(cookies["v"], value);
+    }
+
+    #[test]
+    fn parses_cookie_headers() {
+        let cookies = parse_cookies(["a=1; b=\"two\"; junk; =empty; a=shadowed", "c=%41"]);
+
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["a"], "1");
+        assert_eq!(cookies["b"], "two");
+        assert_eq!(cookies["c"], "A");
+    }
+
+    #[test]
+    fn rejects_short_secrets() {
+        assert!(Key::from_secret(&[0; MIN_SECRET_LEN - 1]).is_err());
+        assert!(Key::from_secret(&[0; MIN_SECRET_LEN]).is_ok());
+    }
+
+    #[test]
+    fn verifies_signed_cookies() {
+        let key = key();
+        let signed = Cookie::new("user", "alice.admin").signed(&key);
+
+        assert!(signed.value.ends_with(".alice.admin"));
+        assert_eq!(
+            key.verify("user", &signed.value).as_deref(),
+            Some("alice.admin")
+        );
+
+        let tampered = signed.value.replace("alice", "mallory");
+        assert!(key.verify("user", &tampered).is_none());
+
+        // The signature covers the name too.
+        assert!(key.verify("other", &signed.value).is_none());
+
+        let other_key = Key::from_secret(b"another secret that is 32 bytes long").unwrap();
+        assert!(other_key.verify("user", &signed.value).is_none());
+
+        assert!(key.verify("user", "alice").is_none());
+        assert!(key.verify("user", "!!!.alice").is_none());
+    }
+
+    #[test]
+    fn decrypts_encrypted_cookies() {
+        let key = key();
+        let first = Cookie::new("session", "secret value").encrypted(&key);
+        let second = Cookie::new("session", "secret value").encrypted(&key);
+
+        assert!(!first.value.contains("secret"));
+        assert_ne!(first.value, second.value, "Nonces must not be reused.");
+        assert_eq!(
+            key.decrypt("session", &first.value).as_deref(),
+            Some("secret value")
+        );
+
+        let mut tampered = URL_SAFE_NO_PAD.decode(&first.value).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key
+            .decrypt("session", &URL_SAFE_NO_PAD.encode(tampered))
+            .is_none());
+
+        assert!(key.decrypt("other", &first.value).is_none());
+
+        let other_key = Key::from_secret(b"another secret that is 32 bytes long").unwrap();
+        assert!(other_key.decrypt("session", &first.value).is_none());
+
+        assert!(key.decrypt("session", "").is_none());
+        assert!(key.decrypt("session", "short").is_none());
+        assert!(key.decrypt("session", "not base64!").is_none());
+    }
+}
@@ -0,0 +1,79 @@
+use super::multimap::MultiMap;
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decodes `%XX` escapes. Invalid escapes are left as they are.
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' && idx + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_value(bytes[idx + 1]), hex_value(bytes[idx + 2]))
+            {
+                output.push(high << 4 | low);
+                idx += 3;
+                continue;
+            }
+        }
+
+        output.push(bytes[idx]);
+        idx += 1;
+    }
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Percent-encodes a single path segment, leaving only the characters a
+/// segment may hold as they are. `/`, `?` and `#` are always encoded.
+pub fn encode_path_segment(segment: &str) -> String {
+    let mut output = String::with_capacity(segment.len());
+
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                output.push(byte as char)
+            }
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':'
+            | b'@' => output.push(byte as char),
+            _ => output.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    output
+}
+
+/// Decodes a request path. A path holding an encoded slash gives `None`,
+/// since once decoded it could not be told apart from a path with one more
+/// segment.
+pub fn decode_path(path: &str) -> Option<String> {
+    if path.contains("%2F") || path.contains("%2f") {
+        return None;
+    }
+
+    Some(percent_decode(path))
+}
+
+/// Parses an `application/x-www-form-urlencoded` string, as used by form
+/// bodies and query strings.
+pub fn parse_urlencoded(input: &str) -> MultiMap {
+    let mut map = MultiMap::new();
+
+    for pair in input.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        map.insert(
+            percent_decode(&key.replace('+', " ")),
+            percent_decode(&value.replace('+', " ")),
+        );
+    }
+
+    map
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{encoding::parse_urlencoded, multimap::MultiMap, parser::ParserLimits};
+use crate::errors::OxidarError;
+
+static UPLOAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A file sent with a `multipart/form-data` form. The file is written to a
+/// temporary directory and removed when the `UploadedFile` is dropped, unless
+/// it has been moved with `persist`.
+///
+/// Uploads are not streamed to disk: the whole request body is read first,
+/// so `Oxidar::max_body_size` also bounds the size of an upload. How many
+/// files a form may upload is set by `ParserLimits::max_form_files`.
+#[derive(Debug)]
+pub struct UploadedFile {
+    pub filename: String,
+    pub content_type: String,
+    pub path: PathBuf,
+    pub size: usize,
+}
+
+impl UploadedFile {
+    fn create(filename: String, content_type: String, data: &[u8]) -> Result<Self, OxidarError> {
+        let dir = std::env::temp_dir().join("oxidar-uploads");
+        OxidarError::nio(fs::create_dir_all(&dir))?;
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos())
+            .unwrap_or_default();
+        let count = UPLOAD_COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}-{nanos}-{count}", std::process::id()));
+
+        // The directory is shared, so the file must be new rather than
+        // something planted at the same path, such as a symlink.
+        let mut file =
+            OxidarError::nio(OpenOptions::new().write(true).create_new(true).open(&path))?;
+
+        let written = file.write_all(data);
+        if written.is_err() {
+            let _ = fs::remove_file(&path);
+        }
+        OxidarError::nio(written)?;
+
+        Ok(UploadedFile {
+            filename,
+            content_type,
+            path,
+            size: data.len(),
+        })
+    }
+
+    /// Moves the file out of the temporary directory to `to`.
+    pub fn persist<P: AsRef<Path>>(&self, to: P) -> Result<(), OxidarError> {
+        if fs::rename(&self.path, &to).is_err() {
+            OxidarError::nio(fs::copy(&self.path, &to))?;
+            OxidarError::nio(fs::remove_file(&self.path))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, OxidarError> {
+        OxidarError::nio(fs::read(&self.path))
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The decoded body of an `application/x-www-form-urlencoded` or
+/// `multipart/form-data` request.
+#[derive(Debug, Default)]
+pub struct Form {
+    pub data: MultiMap,
+    pub files: HashMap<String, Vec<UploadedFile>>,
+}
+
+impl Form {
+    /// Gets the first value of a field.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.data.get(name)
+    }
+
+    /// Gets every value of a field.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.data.get_all(name)
+    }
+
+    /// Gets the first file uploaded under `name`.
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.get(name).and_then(|files| files.first())
+    }
+
+    /// Decodes the body. A multipart form with more parts or files than
+    /// `limits` allow results in a 413 error.
+    pub(crate) fn parse(
+        content_type: Option<&str>,
+        body: &[u8],
+        limits: &ParserLimits,
+    ) -> Result<Form, OxidarError> {
+        let content_type = match content_type {
+            Some(content_type) => content_type,
+            None if body.is_empty() => return Ok(Form::default()),
+            None => {
+                return Err(OxidarError::http_400(Some(
+                    "A form body was sent without a content type.".to_string(),
+                )))
+            }
+        };
+
+        let (mime, params) = split_header_params(content_type);
+
+        match mime.to_lowercase().as_str() {
+            "application/x-www-form-urlencoded" => Ok(Form {
+                data: parse_urlencoded(&String::from_utf8_lossy(body)),
+                files: HashMap::new(),
+            }),
+            "multipart/form-data" => {
+                let boundary = params.get("boundary").ok_or(OxidarError::http_400(Some(
+                    "Multipart form is missing a boundary.".to_string(),
+                )))?;
+
+                parse_multipart(boundary, body, limits)
+            }
+            _ => Err(OxidarError::http_400(Some(format!(
+                "\"{mime}\" is not a form content type."
+            )))),
+        }
+    }
+}
+
+/// Splits a header such as `form-data; name="file"; filename="a.txt"` into its
+/// value and lowercased parameters. Quoted parameters may contain `;`.
+fn split_header_params(header: &str) -> (&str, HashMap<String, String>) {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+
+    for (idx, c) in header.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                parts.push(&header[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&header[start..]);
+
+    let mut params = HashMap::new();
+    for part in &parts[1..] {
+        if let Some((key, value)) = part.split_once('=') {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            params.insert(key.trim().to_lowercase(), value.to_string());
+        }
+    }
+
+    (parts[0].trim(), params)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_multipart(
+    boundary: &str,
+    body: &[u8],
+    limits: &ParserLimits,
+) -> Result<Form, OxidarError> {
+    let malformed = || OxidarError::http_400(Some("Malformed multipart form.".to_string()));
+    let too_many = |what| OxidarError::http_413(Some(format!("The form has too many {what}.")));
+
+    let delimiter = format!("--{boundary}").into_bytes();
+    let mut form = Form::default();
+
+    let mut rest = match find(body, &delimiter) {
+        Some(idx) => &body[idx + delimiter.len()..],
+        None => return Err(malformed()),
+    };
+
+    let delimiter = format!("\r\n--{boundary}").into_bytes();
+    let (mut parts, mut files) = (0, 0);
+    loop {
+        if rest.starts_with(b"--") {
+            break;
+        }
+
+        parts += 1;
+        if parts > limits.max_form_parts {
+            return Err(too_many("parts"));
+        }
+
+        rest = rest.strip_prefix(b"\r\n").ok_or_else(malformed)?;
+
+        let headers_end = find(rest, b"\r\n\r\n").ok_or_else(malformed)?;
+        let headers = String::from_utf8_lossy(&rest[..headers_end]);
+        rest = &rest[headers_end + 4..];
+
+        let content_end = find(rest, &delimiter).ok_or_else(malformed)?;
+        let content = &rest[..content_end];
+        rest = &rest[content_end + delimiter.len()..];
+
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in headers.split("\r\n") {
+            if let Some((key, value)) = line.split_once(':') {
+                match key.trim().to_lowercase().as_str() {
+                    "content-disposition" => disposition = Some(split_header_params(value).1),
+                    "content-type" => content_type = Some(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+        }
+
+        let disposition = disposition.ok_or_else(malformed)?;
+        let name = disposition.get("name").ok_or_else(malformed)?.clone();
+
+        match disposition.get("filename") {
+            Some(filename) => {
+                if filename.is_empty() && content.is_empty() {
+                    continue;
+                }
+
+                // Files are written to disk, so they are counted before the
+                // next one is.
+                files += 1;
+                if files > limits.max_form_files {
+                    return Err(too_many("files"));
+                }
+
+                let file = UploadedFile::create(
+                    filename.clone(),
+                    content_type.unwrap_or("application/octet-stream".to_string()),
+                    content,
+                )?;
+                form.files.entry(name).or_default().push(file);
+            }
+            None => form
+                .data
+                .insert(name, String::from_utf8_lossy(content).into_owned()),
+        }
+    }
+
+    Ok(form)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        errors::Error,
+        server::{
+            http::{Method, StatusCode},
+            request::test_request,
+        },
+    };
+
+    const MULTIPART: &str = "multipart/form-data; boundary=XyZ";
+    const BODY: &[u8] = b"--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        Hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"doc\"; filename=\"a;b.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        file\r\ncontents\r\n\
+        --XyZ--\r\n";
+
+    fn parse(content_type: Option<&str>, body: &[u8]) -> Result<Form, OxidarError> {
+        Form::parse(content_type, body, &ParserLimits::default())
+    }
+
+    #[test]
+    fn parses_urlencoded_forms() {
+        let form = parse(
+            Some("application/x-www-form-urlencoded; charset=utf-8"),
+            b"a=1&b=two+words&a=3",
+        )
+        .unwrap();
+
+        assert_eq!(form.get_all("a"), vec!["1", "3"]);
+        assert_eq!(form.get("b"), Some("two words"));
+        assert!(form.files.is_empty());
+    }
+
+    #[test]
+    fn parses_multipart_forms() {
+        let form = parse(Some(MULTIPART), BODY).unwrap();
+        assert_eq!(form.get("title"), Some("Hello"));
+
+        let file = form.file("doc").unwrap();
+        assert_eq!(file.filename, "a;b.txt");
+        assert_eq!(file.content_type, "text/plain");
+        assert_eq!(file.size, 14);
+        assert_eq!(file.read().unwrap(), b"file\r\ncontents");
+
+        let path = file.path.clone();
+        drop(form);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rejects_malformed_forms() {
+        assert!(parse(None, b"").unwrap().data.get("a").is_none());
+        assert!(parse(None, b"a=1").is_err());
+        assert!(parse(Some("text/plain"), b"a=1").is_err());
+        assert!(parse(Some("multipart/form-data"), BODY).is_err());
+        assert!(parse(Some(MULTIPART), &BODY[..BODY.len() - 12]).is_err());
+    }
+
+    #[test]
+    fn limits_the_parts_and_files_of_forms() {
+        let status = |limits: ParserLimits| match Form::parse(Some(MULTIPART), BODY, &limits) {
+            Err(OxidarError::Normal(Error::Http(status, _))) => Some(status),
+            _ => None,
+        };
+        let limits = |max_form_parts, max_form_files| ParserLimits {
+            max_form_parts,
+            max_form_files,
+            ..ParserLimits::default()
+        };
+
+        assert_eq!(status(limits(2, 1)), None);
+        assert_eq!(status(limits(1, 1)), Some(StatusCode::CONTENT_TOO_LARGE));
+        assert_eq!(status(limits(2, 0)), Some(StatusCode::CONTENT_TOO_LARGE));
+    }
+
+    #[test]
+    fn requests_decode_their_form_once() {
+        let request = test_request(Method::POST, "/", &[("Content-Type", MULTIPART)], BODY);
+
+        let first = request.form().unwrap().file("doc").unwrap().path.clone();
+        let second = request.form().unwrap().file("doc").unwrap().path.clone();
+        assert_eq!(first, second);
+        assert!(first.exists());
+
+        drop(request);
+        assert!(!first.exists());
+    }
+}
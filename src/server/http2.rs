@@ -0,0 +1,410 @@
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use h2::{
+    server::{self, SendResponse},
+    RecvStream, SendStream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    task::JoinSet,
+    time::timeout,
+};
+
+use super::{
+    http::{Headers, Method, StatusCode, Version},
+    parser::{timed_out, ParsedRequest},
+    request::Request,
+    response::Body,
+    Oxidar, IDLE_POLL_INTERVAL,
+};
+use crate::errors::{Error, OxidarError};
+
+/// The first bytes a client sends on an HTTP/2 connection.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers that only mean something for a single HTTP/1 connection, which
+/// HTTP/2 forbids.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A stream that hands back bytes already read from it before reading more.
+pub(crate) struct Rewind<S> {
+    read: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.pos < this.read.len() {
+            let end = this.read.len().min(this.pos + buf.remaining());
+            buf.put_slice(&this.read[this.pos..end]);
+            this.pos = end;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads from the connection until it is clear whether the client started
+/// with the HTTP/2 preface, meaning it has prior knowledge that the server
+/// speaks HTTP/2 without TLS. The bytes read are handed back by the returned
+/// stream either way.
+pub(crate) async fn sniff_preface<S: AsyncRead + Unpin>(
+    mut stream: S,
+    wait: Duration,
+) -> Result<(bool, Rewind<S>), OxidarError> {
+    let mut read = Vec::with_capacity(PREFACE.len());
+    let started = Instant::now();
+
+    while read.len() < PREFACE.len() && PREFACE.starts_with(&read) {
+        let mut buf = [0; PREFACE.len()];
+        let wanted = PREFACE.len() - read.len();
+        let remaining = wait.saturating_sub(started.elapsed());
+
+        match timeout(remaining, stream.read(&mut buf[..wanted])).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => read.extend_from_slice(&buf[..n]),
+            Ok(Err(err)) => return Err(OxidarError::Abortion(Error::Io(err))),
+        }
+    }
+
+    let prior_knowledge = read == PREFACE;
+    let stream = Rewind {
+        read,
+        pos: 0,
+        inner: stream,
+    };
+
+    Ok((prior_knowledge, stream))
+}
+
+/// Sends data on a stream as the peer's flow control window allows, so a
+/// streamed body is not buffered up faster than the client reads it.
+async fn send_data(
+    send: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    end_of_stream: bool,
+) -> Result<(), OxidarError> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(capacity) => capacity.map_err(h2_error)?,
+            None => {
+                return Err(OxidarError::Abortion(Error::Untyped(
+                    "The HTTP/2 stream was closed.".to_string(),
+                )))
+            }
+        };
+
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk, false).map_err(h2_error)?;
+    }
+
+    if end_of_stream {
+        send.send_data(Bytes::new(), true).map_err(h2_error)?;
+    }
+
+    Ok(())
+}
+
+fn h2_error(err: h2::Error) -> OxidarError {
+    let message = err.to_string();
+    match err.into_io() {
+        Some(err) => OxidarError::Abortion(Error::Io(err)),
+        None => OxidarError::Abortion(Error::Untyped(format!("HTTP/2 error: {message}"))),
+    }
+}
+
+impl Oxidar {
+    /// Serves an HTTP/2 connection. Each stream is dispatched to the views on
+    /// its own task, so one slow view does not hold up the others. Streams
+    /// past the concurrent stream limit are refused.
+    pub(crate) async fn serve_h2<S>(self: Arc<Self>, stream: S) -> Result<(), OxidarError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let header_list_size = self.limits.max_header_line * self.limits.max_header_count;
+        let handshake = server::Builder::new()
+            .max_concurrent_streams(self.max_concurrent_streams)
+            .max_header_list_size(header_list_size.min(u32::MAX as usize) as u32)
+            .handshake(stream);
+
+        let mut connection = match timeout(self.keep_alive_timeout, handshake).await {
+            Ok(connection) => connection.map_err(h2_error)?,
+            Err(_) => return Err(timed_out()),
+        };
+
+        let mut streams = JoinSet::new();
+        let mut idle_since = Instant::now();
+        let mut closing = false;
+
+        // The connection only makes progress while it is being polled, so it
+        // is polled in short slices to notice shutdowns and idle timeouts.
+        loop {
+            while streams.try_join_next().is_some() {}
+            if !streams.is_empty() {
+                idle_since = Instant::now();
+            }
+
+            if !closing
+                && (self.shutdown.is_shutting_down()
+                    || idle_since.elapsed() >= self.keep_alive_timeout)
+            {
+                connection.graceful_shutdown();
+                closing = true;
+            }
+
+            let (request, respond) = match timeout(IDLE_POLL_INTERVAL, connection.accept()).await {
+                Ok(Some(stream)) => stream.map_err(h2_error)?,
+                Ok(None) => break,
+                Err(_) => continue,
+            };
+
+            let oxidar = self.clone();
+            streams.spawn(async move {
+                if let Err(err) = oxidar.respond_h2(request, respond).await {
+                    oxidar.log_connection_error(err);
+                }
+            });
+        }
+
+        while streams.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    async fn respond_h2(
+        &self,
+        request: http::Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+    ) -> Result<(), OxidarError> {
+        let head = request.method() == http::Method::HEAD;
+
+        let request = self.read_h2_request(request).await.and_then(|parsed| {
+            Request::new(parsed, self.urls.clone(), self.state.clone(), &self.limits)
+        });
+
+        let (response, err) = match request {
+            Ok(request) => self.dispatch_async(request).await?,
+            Err(err) => Self::settle(Err(err))?,
+        };
+
+        let (status, fields, body) = response.into_parts();
+        let mut builder = http::Response::builder().status(status.as_u16());
+        for (name, value) in fields.iter() {
+            if !CONNECTION_HEADERS.contains(&name.to_lowercase().as_str()) {
+                builder = builder.header(name, value);
+            }
+        }
+
+        let response = builder.body(()).map_err(|err| {
+            OxidarError::Abortion(Error::Untyped(format!("Invalid response head: {err}")))
+        })?;
+
+        let end_of_stream = head || matches!(body, Body::Full(ref body) if body.is_empty());
+        let mut send = respond
+            .send_response(response, end_of_stream)
+            .map_err(h2_error)?;
+
+        if !end_of_stream {
+            match body {
+                Body::Full(body) => send_data(&mut send, Bytes::from(body), true).await?,
+                Body::Chunks(mut chunks) => {
+                    while let Some(chunk) = tokio::task::block_in_place(|| chunks.next()) {
+                        match chunk {
+                            Ok(chunk) => send_data(&mut send, Bytes::from(chunk), false).await?,
+                            Err(err) => {
+                                send.send_reset(h2::Reason::INTERNAL_ERROR);
+                                return Err(OxidarError::Abortion(Error::Untyped(format!(
+                                    "The response body ended early: {err}"
+                                ))));
+                            }
+                        }
+                    }
+                    send_data(&mut send, Bytes::new(), true).await?;
+                }
+            }
+        }
+
+        match err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Turns an HTTP/2 request into the form the HTTP/1 parser produces,
+    /// reading its whole body.
+    async fn read_h2_request(
+        &self,
+        request: http::Request<RecvStream>,
+    ) -> Result<ParsedRequest, OxidarError> {
+        let (parts, mut stream) = request.into_parts();
+
+        let method = Method::try_from(parts.method.as_str()).or(Err(OxidarError::http(
+            StatusCode::NOT_IMPLEMENTED,
+            Some(format!("The method \"{}\" is not supported.", parts.method)),
+        )))?;
+
+        let uri = match parts.uri.path_and_query() {
+            Some(path) => path.as_str().to_string(),
+            None => "/".to_string(),
+        };
+
+        let mut headers = Headers::new();
+        for (name, value) in parts.headers.iter() {
+            headers.append(name.as_str(), &String::from_utf8_lossy(value.as_bytes()));
+        }
+
+        // `:authority` takes the place of `Host`.
+        if let Some(authority) = parts.uri.authority() {
+            if !headers.contains("Host") {
+                headers.insert("Host", authority.as_str());
+            }
+        }
+
+        let mut body = Vec::new();
+        while let Some(data) = stream.data().await {
+            let data = data.map_err(h2_error)?;
+
+            if body.len().saturating_add(data.len()) > self.limits.max_body_size {
+                return Err(OxidarError::http_413(None));
+            }
+
+            body.extend_from_slice(&data);
+            let _ = stream.flow_control().release_capacity(data.len());
+        }
+
+        Ok(ParsedRequest {
+            method,
+            uri,
+            version: Version::Http2_0,
+            headers,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use h2::client;
+    use tokio::{io::DuplexStream, runtime::Builder};
+
+    use super::*;
+    use crate::server::{
+        app::{App, AppReg, ViewReg},
+        LogStyle,
+    };
+
+    /// Polls the client connection alongside `future`, which needs it to make
+    /// progress.
+    async fn drive<F: Future + Unpin>(
+        connection: &mut client::Connection<DuplexStream>,
+        mut future: F,
+    ) -> F::Output {
+        poll_fn(|cx| {
+            let _ = Pin::new(&mut *connection).poll(cx);
+            Pin::new(&mut future).poll(cx)
+        })
+        .await
+    }
+
+    fn sniff(input: &[u8]) -> (bool, Vec<u8>) {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let (prior_knowledge, mut stream) = sniff_preface(input, Duration::from_millis(50))
+                .await
+                .unwrap();
+            let mut read = Vec::new();
+            stream.read_to_end(&mut read).await.unwrap();
+            (prior_knowledge, read)
+        })
+    }
+
+    #[test]
+    fn sniffs_the_preface_and_rewinds() {
+        let mut input = PREFACE.to_vec();
+        input.extend_from_slice(b"frames");
+        assert_eq!(sniff(&input), (true, input.clone()));
+
+        let input = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert_eq!(sniff(input), (false, input.to_vec()));
+        assert_eq!(sniff(&PREFACE[..10]), (false, PREFACE[..10].to_vec()));
+    }
+
+    #[test]
+    fn serves_streams_and_limits_them() {
+        let app = App::new(vec![ViewReg::get("/hello", |_, request| {
+            match request.version {
+                Version::Http2_0 => "hello over h2",
+                _ => "hello",
+            }
+        })]);
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("/", app)],
+            "127.0.0.1:0",
+            1,
+            LogStyle::Terminal,
+            false,
+        )
+        .max_concurrent_streams(2);
+
+        let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(Arc::new(oxidar).serve_h2(server));
+
+            let (mut send, mut connection) = client::handshake(client).await.unwrap();
+            let request = http::Request::get("http://localhost/hello")
+                .body(())
+                .unwrap();
+            let (response, _) = send.send_request(request, true).unwrap();
+            let response = drive(&mut connection, response).await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(connection.max_concurrent_send_streams(), 2);
+
+            let mut body = response.into_body();
+            let data = drive(&mut connection, Box::pin(body.data())).await;
+            assert_eq!(&data.unwrap().unwrap()[..], b"hello over h2");
+        });
+    }
+}
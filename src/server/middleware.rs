@@ -0,0 +1,96 @@
+use super::{request::Request, response::Response};
+use crate::errors::OxidarError;
+
+/// Code that runs around the views, registered for every request with
+/// `Oxidar::middleware` or for one app with `AppReg::middleware`.
+///
+/// A middleware is handed the request and the rest of the chain. It may
+/// change the request before passing it on with `next.run`, change the
+/// response that comes back, or answer by itself without calling `next` at
+/// all. Middleware runs in the order it was registered, with the global
+/// middleware around the app's. Errors from further down the chain come back
+/// through `next.run`, so they can be handled too.
+///
+/// ```ignore
+/// struct RequireToken(String);
+///
+/// impl Middleware for RequireToken {
+///     fn handle(&self, request: Request, next: Next) -> Result<Response, OxidarError> {
+///         match request.header("Authorization") {
+///             Some(token) if token == self.0 => next.run(request),
+///             _ => Err(OxidarError::http(StatusCode::UNAUTHORIZED, None)),
+///         }
+///     }
+/// }
+/// ```
+///
+/// Global middleware runs before the request is tied to an app, so it may
+/// rewrite the path to change where the request is routed.
+///
+/// WebSocket upgrade requests pass through middleware too, before the
+/// handshake. Returning a response without calling `next` turns the upgrade
+/// away, and headers added to the response that comes back from `next.run`
+/// are sent with the handshake.
+///
+/// Middleware is blocking. Under `Oxidar::run_async`, a request that any
+/// middleware applies to runs its whole chain, async view included, on a
+/// runtime worker set aside with `block_in_place`, the way a blocking view
+/// does. Such requests no longer share the worker while the view awaits, so
+/// apps with many slow async views may want to keep middleware to the apps
+/// that need it.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: Request, next: Next) -> Result<Response, OxidarError>;
+}
+
+/// The rest of a middleware chain, ending in the view.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    endpoint: &'a dyn Fn(Request) -> Result<Response, OxidarError>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middleware: &'a [Box<dyn Middleware>],
+        endpoint: &'a dyn Fn(Request) -> Result<Response, OxidarError>,
+    ) -> Next<'a> {
+        Next {
+            middleware,
+            endpoint,
+        }
+    }
+
+    /// Passes the request on to the next middleware, or to the view once
+    /// every middleware has run, and returns its response.
+    pub fn run(self, request: Request) -> Result<Response, OxidarError> {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// A middleware written as a closure, made with `from_fn`.
+pub struct FnMiddleware<F>(F);
+
+impl<F> Middleware for FnMiddleware<F>
+where
+    F: Fn(Request, Next) -> Result<Response, OxidarError> + Send + Sync,
+{
+    fn handle(&self, request: Request, next: Next) -> Result<Response, OxidarError> {
+        (self.0)(request, next)
+    }
+}
+
+/// Makes a middleware out of a closure.
+///
+/// ```ignore
+/// Oxidar::new(apps, "127.0.0.1:8000", 4, LogStyle::Terminal, false).middleware(from_fn(
+///     |request, next| Ok(next.run(request)?.header("X-Frame-Options", "DENY")),
+/// ))
+/// ```
+pub fn from_fn<F>(f: F) -> FnMiddleware<F>
+where
+    F: Fn(Request, Next) -> Result<Response, OxidarError> + Send + Sync,
+{
+    FnMiddleware(f)
+}
@@ -1,24 +1,64 @@
 pub mod app;
+#[cfg(feature = "async")]
+mod async_server;
+pub mod cookie;
+mod date;
+mod encoding;
+pub mod form;
 pub mod http;
+#[cfg(feature = "http2")]
+mod http2;
+pub mod middleware;
+pub mod mime;
+pub mod multimap;
+pub mod parser;
+pub mod path;
 pub mod request;
 pub mod response;
+pub mod shutdown;
+pub mod sse;
+pub mod state;
+pub mod static_files;
+pub mod stats;
+mod stream;
 mod thread_pool;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod urls;
+pub mod websocket;
 
-use crate::errors::OxidarError;
+use crate::errors::{Error, OxidarError};
 use app::AppReg;
-use http::{Method, Version};
+use cookie::Key;
+use http::{Method, StatusCode, Version};
+use middleware::{Middleware, Next};
+use parser::{parse_request, ParserLimits};
 use request::Request;
-use response::Response;
+use response::{Chunks, Response};
+use shutdown::{install_signal_handlers, ShutdownHandle};
+use state::State;
+use stats::PoolStats;
+use std::any::Any;
 use std::fmt::Display;
 use std::path::PathBuf;
 use std::{
-    collections::HashMap,
-    io::{prelude::BufRead, BufReader, Write},
+    io::{prelude::BufRead, BufReader, ErrorKind, Read, Write},
     net::{TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
     str,
     sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
+use stream::{Acceptor, Stream};
 use thread_pool::ThreadPool;
+#[cfg(feature = "tls")]
+use tls::TlsConfig;
+use urls::Urls;
+use websocket::{is_upgrade, Buffered, Upgrade};
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 pub enum LogMethod {
     Info,
@@ -34,10 +74,26 @@ pub enum LogStyle {
 
 pub struct Oxidar {
     apps: Vec<AppReg>,
+    urls: Arc<Urls>,
+    state: Arc<State>,
     socket_addr: &'static str,
     threads: usize,
     log_method: LogStyle,
     debug: bool,
+    limits: ParserLimits,
+    keep_alive_timeout: Duration,
+    max_keep_alive_requests: usize,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    handle_signals: bool,
+    queue_depth: usize,
+    retry_after: Duration,
+    stats: PoolStats,
+    middleware: Vec<Box<dyn Middleware>>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    #[cfg(feature = "http2")]
+    max_concurrent_streams: u32,
 }
 
 impl Oxidar {
@@ -49,148 +105,558 @@ impl Oxidar {
         debug: bool,
     ) -> Self {
         let oxidar = Self {
+            urls: Arc::new(Urls::new(&apps)),
+            state: Arc::new(State::new()),
             apps,
             threads,
             socket_addr,
             log_method,
             debug,
+            limits: ParserLimits::default(),
+            keep_alive_timeout: Duration::from_secs(5),
+            max_keep_alive_requests: 100,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: Duration::from_secs(30),
+            handle_signals: false,
+            queue_depth: 1024,
+            retry_after: Duration::from_secs(1),
+            stats: PoolStats::new(),
+            middleware: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "http2")]
+            max_concurrent_streams: 100,
         };
 
         oxidar.log(format!("Oxidar app created."));
         return oxidar;
     }
+
+    /// Stores a value shared by every view, which they can get with
+    /// `Request::state`. Values are looked up by type.
+    pub fn with_state<T: Any + Send + Sync>(mut self, value: T) -> Self {
+        match Arc::get_mut(&mut self.state) {
+            Some(state) => state.insert(value),
+            None => unreachable!("State is only shared once the server is running."),
+        }
+        self
+    }
+
+    /// Sets the key used to sign and encrypt cookies. Views get it with
+    /// `Request::key` to build signed and encrypted cookies, and the
+    /// `Request::signed_cookie` and `Request::private_cookie` methods use it to
+    /// read them back.
+    pub fn secret_key(self, key: Key) -> Self {
+        self.with_state(key)
+    }
+
+    /// Sets the largest request body, in bytes, that will be read. Larger
+    /// bodies are rejected with a 413 response. Defaults to 10 MiB.
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.limits.max_body_size = bytes;
+        self
+    }
+
+    /// Sets the limits used while parsing requests.
+    pub fn parser_limits(mut self, limits: ParserLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets how long an idle connection is kept open waiting for another
+    /// request. Defaults to 5 seconds.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Sets how many requests are served on one connection before it is
+    /// closed. Defaults to 100, and 1 turns keep alive off.
+    pub fn max_keep_alive_requests(mut self, requests: usize) -> Self {
+        self.max_keep_alive_requests = requests.max(1);
+        self
+    }
+
+    /// Gets a handle that can stop the server once it is running.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Adds middleware that runs around every view of every app. Middleware
+    /// runs in the order it is added.
+    pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Sets how long requests in flight are given to finish once a shutdown
+    /// starts. Defaults to 30 seconds.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Sets whether SIGINT and SIGTERM start a graceful shutdown, in which
+    /// case a second signal ends the process right away. Off by default, as
+    /// it replaces any handlers the process has for them.
+    pub fn handle_signals(mut self, handle_signals: bool) -> Self {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    /// Sets how many connections may wait for a free worker. Connections that
+    /// arrive while the queue is full get a 503 response. Defaults to 1024,
+    /// and is at least 1.
+    ///
+    /// Over TLS, connections that arrive while the queue is full are closed
+    /// without a response, as answering would mean a TLS handshake on the
+    /// thread accepting connections. Only `run` has a queue: `run_async`
+    /// gives every connection its own task and ignores this limit.
+    pub fn queue_depth(mut self, depth: usize) -> Self {
+        self.queue_depth = depth.max(1);
+        self
+    }
+
+    /// Sets the `Retry-After` time sent with 503 responses when the queue is
+    /// full. Defaults to 1 second. See `queue_depth` for when they are sent.
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Sets how many streams an HTTP/2 client may have open at once on one
+    /// connection. Further streams are refused. Defaults to 100.
+    #[cfg(feature = "http2")]
+    pub fn max_concurrent_streams(mut self, streams: u32) -> Self {
+        self.max_concurrent_streams = streams.max(1);
+        self
+    }
+
+    /// Gets live counts of queued connections and busy workers.
+    pub fn stats(&self) -> PoolStats {
+        self.stats.clone()
+    }
+
+    /// Serves requests over TLS instead of plain TCP.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Builds the full url of a named view, such as
+    /// `oxidar.reverse("blog:post_detail", &[("id", 5)])`.
+    pub fn reverse<T: Display>(
+        &self,
+        name: &str,
+        args: &[(&str, T)],
+    ) -> Result<String, OxidarError> {
+        self.urls.reverse(name, args)
+    }
+
+    /// Serves requests until a shutdown is started with a `ShutdownHandle` or,
+    /// if `handle_signals` is on, a signal, then stops accepting connections, waits up to the shutdown
+    /// timeout for requests in flight, and returns.
     pub fn run(self) -> Result<(), OxidarError> {
         let oxidar = Arc::new(self);
         let listener = OxidarError::fio(TcpListener::bind(oxidar.socket_addr))?;
-        let pool = ThreadPool::new(oxidar.clone(), oxidar.threads);
+        OxidarError::fio(listener.set_nonblocking(true))?;
+
+        let acceptor = Acceptor::new(&oxidar, false)?;
+
+        if oxidar.handle_signals {
+            install_signal_handlers(&oxidar.shutdown);
+        }
+
+        let mut pool = ThreadPool::new(oxidar.clone(), oxidar.threads, oxidar.queue_depth)?;
         oxidar.log(format!(
             "Started server. Listening on {}",
             oxidar.socket_addr
         ));
 
-        for stream in listener.incoming() {
-            let stream = OxidarError::fio(stream)?;
+        while !oxidar.shutdown.is_shutting_down() {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(err) => {
+                    oxidar.loge(format!("Could not accept a connection: {err}"));
+                    continue;
+                }
+            };
+
+            if let Err(err) = stream.set_nonblocking(false) {
+                oxidar.loge(format!("Could not set up a connection: {err}"));
+                continue;
+            }
+
+            if pool.is_full() {
+                match acceptor.is_tls() {
+                    // Answering would mean a TLS handshake on the accepting
+                    // thread, so the connection is just closed.
+                    true => oxidar.logw("The job queue is full. Closing connection.".to_string()),
+                    false => oxidar.reject_overloaded(stream),
+                }
+                continue;
+            }
+
+            let acceptor = acceptor.clone();
             let oxidar = oxidar.clone();
             pool.execute(move || {
-                if let Err(err) = oxidar.handel_connection(stream) {
-                    match err {
-                        OxidarError::Fatal(error) => {
-                            todo!()
-                        }
-                        OxidarError::Abortion(error) => {
-                            oxidar.loge(format!("Oxidar is aborting the request: {error}"))
-                        }
-                        OxidarError::Normal(error) => oxidar.loge(error),
-                    };
+                let result = acceptor
+                    .accept(stream)
+                    .and_then(|stream| oxidar.handel_connection(stream));
+
+                if let Err(err) = result {
+                    oxidar.log_connection_error(err);
                 }
             })
         }
 
+        oxidar.log("Shutting down. No longer accepting connections.".to_string());
+        drop(listener);
+        pool.shutdown(oxidar.shutdown_timeout);
+        oxidar.log("Shutdown complete.".to_string());
+
         return Ok(());
     }
 
-    fn handel_connection(&self, stream: TcpStream) -> Result<(), OxidarError> {
-        let buffer = BufReader::new(&stream);
-        let mut lines = buffer.lines();
+    fn log_connection_error(&self, err: OxidarError) {
+        match err {
+            OxidarError::Fatal(error) => {
+                self.loge(format!("Fatal error while handling a connection: {error}"))
+            }
+            OxidarError::Abortion(error) => {
+                self.loge(format!("Oxidar is aborting the request: {error}"))
+            }
+            OxidarError::Normal(error) => self.loge(error),
+        };
+    }
+
+    /// Turns a connection away with a 503 response because every worker is
+    /// busy and the queue is full.
+    fn reject_overloaded(&self, mut stream: TcpStream) {
+        self.logw("The job queue is full. Responding with 503.".to_string());
+
+        let err = OxidarError::http(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some("The server is too busy. Please try again soon.".to_string()),
+        );
+
+        if let Some(response) = err.to_response() {
+            let response = response
+                .header(
+                    "Retry-After",
+                    &self.retry_after.as_secs().max(1).to_string(),
+                )
+                .header("Connection", "close");
+
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let _ = stream.write_all(&response.build_response());
+            let _ = stream.flush();
+        }
+
+        // Closing with the request unread would reset the connection, which
+        // can throw the response away before the client reads it. Only what
+        // has already arrived is read, so the accepting thread never waits.
+        if stream.set_nonblocking(true).is_ok() {
+            let mut buf = [0; 4096];
+            while matches!(stream.read(&mut buf), Ok(n) if n > 0) {}
+        }
+    }
+
+    /// Serves requests from the connection until the client closes it, asks
+    /// for it to be closed, sits idle past the keep alive timeout, or reaches
+    /// the request limit. Pipelined requests are answered in order.
+    fn handel_connection(&self, stream: Stream) -> Result<(), OxidarError> {
+        OxidarError::fio(stream.tcp().set_read_timeout(Some(self.keep_alive_timeout)))?;
+        let mut reader = BufReader::new(stream);
+        let mut served = 0;
+
+        loop {
+            if !self.wait_for_request(&mut reader, served == 0)? {
+                return Ok(());
+            }
 
-        let mut request: Option<Request> = None;
+            let request = parse_request(&mut reader, &self.limits).and_then(|parsed| {
+                parsed
+                    .map(|parsed| {
+                        Request::new(parsed, self.urls.clone(), self.state.clone(), &self.limits)
+                    })
+                    .transpose()
+            });
 
-        while let Some(line) = lines.next() {
-            let line = OxidarError::fio(line)?;
-            match request {
-                Some(ref mut request) => {
-                    if line.is_empty() {
-                        break;
+            let request = match request {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    if let Some(response) = err.to_response() {
+                        let response = response.header("Connection", "close");
+                        let stream = reader.get_mut();
+                        OxidarError::aio(stream.write_all(&response.build_response()))?;
+                        OxidarError::aio(stream.flush())?;
                     }
 
-                    let (key, val) = line.split_once(": ").ok_or(OxidarError::abort_std(
-                        format!("Malformed Request: Header \"{line}\" could not be parsed."),
-                    ))?;
+                    return Err(err);
+                }
+            };
+
+            served += 1;
+            let keep_alive = served < self.max_keep_alive_requests
+                && !self.shutdown.is_shutting_down()
+                && Self::wants_keep_alive(&request);
 
-                    request.headers.insert(key.to_owned(), val.to_owned());
+            let keep_alive = match is_upgrade(&request) {
+                true => {
+                    let (head, version) = (request.method == Method::HEAD, request.version);
+                    match self.upgrade_websocket(request) {
+                        Upgrade::Accept(app, socket, request, headers) => {
+                            let stream = reader.get_ref().tcp();
+                            OxidarError::fio(stream.set_read_timeout(Some(IDLE_POLL_INTERVAL)))?;
+                            let stream = Box::new(Buffered(&mut reader));
+                            return self.serve_websocket(app, socket, *request, headers, stream);
+                        }
+                        Upgrade::Respond(result) => {
+                            let stream = reader.get_mut();
+                            self.send_response(stream, result, head, version, keep_alive)?
+                        }
+                    }
                 }
-                None => {
-                    let mut split = line.split(" ");
+                false => self.route_to_app(reader.get_mut(), request, keep_alive)?,
+            };
 
-                    let method = split.next().ok_or(OxidarError::abort_std(format!(
-                        "Malformed Request: Method could not be found."
-                    )))?;
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
 
-                    let uri = split.next().ok_or(OxidarError::abort_std(format!(
-                        "Malformed Request: URI could not be found."
-                    )))?;
+    /// Blocks until the next request starts to arrive. Returns false if the
+    /// client closed the connection, the keep alive timeout passed first, or
+    /// the server is shutting down while the connection sits idle between
+    /// requests. The first request of a connection is always waited for, as
+    /// the connection was accepted before the shutdown began.
+    fn wait_for_request(
+        &self,
+        reader: &mut BufReader<Stream>,
+        first: bool,
+    ) -> Result<bool, OxidarError> {
+        if !reader.buffer().is_empty() {
+            return Ok(true);
+        }
 
-                    let version = split.next().ok_or(OxidarError::abort_std(format!(
-                        "Malformed Request: HTTP version could not be found."
-                    )))?;
+        let started = Instant::now();
+        let mut waiting = true;
 
-                    if let Some(_) = split.next() {
-                        return Err(OxidarError::abort_std(format!(
-                            "Malformed Request: Items found after version."
-                        )));
-                    }
+        // The wait is split into short reads so a shutdown is noticed by idle
+        // connections too.
+        let ready = loop {
+            if !first && self.shutdown.is_shutting_down() {
+                break Ok(false);
+            }
 
-                    request = Some(Request {
-                        method: Method::try_from(method)?,
-                        uri: uri.to_owned(),
-                        version: Version::try_from(version)?,
-                        headers: HashMap::new(),
-                    });
+            let remaining = self.keep_alive_timeout.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                break Ok(false);
+            }
+
+            let timeout = remaining.min(IDLE_POLL_INTERVAL);
+            OxidarError::fio(reader.get_ref().tcp().set_read_timeout(Some(timeout)))?;
+            match reader.fill_buf() {
+                Ok(buf) => break Ok(!buf.is_empty()),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => {
+                    waiting = false;
+                    break OxidarError::fio(Err(err));
                 }
             }
+        };
+
+        if waiting {
+            let stream = reader.get_ref().tcp();
+            OxidarError::fio(stream.set_read_timeout(Some(self.keep_alive_timeout)))?;
         }
 
-        let request =
-            request.ok_or(OxidarError::abort_std(format!("No data found in request.")))?;
+        ready
+    }
 
-        return self.route_to_app(stream, request);
+    fn wants_keep_alive(request: &Request) -> bool {
+        let connection = request.header("Connection").unwrap_or_default();
+        let has_token = |token: &str| {
+            connection
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+
+        match request.version {
+            Version::Http1_1 => !has_token("close"),
+            Version::Http1_0 => has_token("keep-alive"),
+            _ => false,
+        }
     }
 
-    fn route_to_app(&self, mut stream: TcpStream, request: Request) -> Result<(), OxidarError> {
+    /// Answers the request and returns whether the connection can be kept
+    /// open for another.
+    fn route_to_app(
+        &self,
+        stream: &mut Stream,
+        request: Request,
+        keep_alive: bool,
+    ) -> Result<bool, OxidarError> {
         self.log(format!("Processing: {} {}", request.method, request.uri));
 
-        let uri = request.uri.clone() + "/";
-        for app in &self.apps {
-            if uri.starts_with(&app.0) {
-                let app_response = app.1.respond(&self, request);
-                match app_response {
-                    Ok(response) => {
-                        OxidarError::aio(stream.write_all(response.build_response().as_bytes()))?;
-                        OxidarError::aio(stream.flush())?;
-                        return Ok(());
-                    }
-                    Err(err) => {
-                        OxidarError::aio(
-                            stream.write_all(
-                                match err.to_response() {
-                                    Some(response) => response,
-                                    None => return Err(err),
-                                }
-                                .build_response()
-                                .as_bytes(),
-                            ),
-                        )?;
-                        OxidarError::aio(stream.flush())?;
-                        return Err(err);
-                    }
-                };
+        let head = request.method == Method::HEAD;
+        let version = request.version;
+
+        let (method, uri) = (request.method, request.uri.clone());
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| self.respond(request))) {
+            Ok(result) => result,
+            Err(payload) => Err(self.view_panicked(method, &uri, &*payload)),
+        };
+
+        self.send_response(stream, result, head, version, keep_alive)
+    }
+
+    /// Writes the response to a request, returning whether the connection
+    /// can be kept open.
+    fn send_response(
+        &self,
+        stream: &mut Stream,
+        result: Result<Response, OxidarError>,
+        head: bool,
+        version: Version,
+        keep_alive: bool,
+    ) -> Result<bool, OxidarError> {
+        let (response, err) = Self::settle(result)?;
+
+        let (raw, chunks, keep_alive) = self.finish_response(response, head, version, keep_alive);
+        OxidarError::aio(stream.write_all(&raw))?;
+        OxidarError::aio(stream.flush())?;
+
+        for chunk in chunks.into_iter().flatten() {
+            OxidarError::aio(stream.write_all(&Self::stream_chunk(chunk)?))?;
+            OxidarError::aio(stream.flush())?;
+        }
+
+        if let Some(OxidarError::Normal(error)) = err {
+            self.loge(error);
+        }
+
+        Ok(keep_alive)
+    }
+
+    /// Runs the request through the global middleware, then the middleware
+    /// and views of its app.
+    fn respond(&self, request: Request) -> Result<Response, OxidarError> {
+        let route = |request: Request| match self.find_app(&request.path) {
+            Some((app, path)) => {
+                let view = |request| app.app.respond(self, &path, request);
+                Next::new(&app.middleware, &view).run(request)
             }
+            None => Self::no_app(),
+        };
+
+        Next::new(&self.middleware, &route).run(request)
+    }
+
+    /// Whether any middleware would run for a request to `path`.
+    #[cfg(feature = "async")]
+    fn has_middleware(&self, path: &str) -> bool {
+        !self.middleware.is_empty()
+            || self
+                .find_app(path)
+                .is_some_and(|(app, _)| !app.middleware.is_empty())
+    }
+
+    /// Finds the app whose prefix the request path starts with, along with the
+    /// part of the path left over once the prefix is removed.
+    fn find_app(&self, request_path: &str) -> Option<(&AppReg, String)> {
+        let path = request_path.to_string() + "/";
+        self.apps
+            .iter()
+            .find(|app| path.starts_with(&app.prefix))
+            .map(|app| (app, request_path[app.prefix.len() - 1..].to_string()))
+    }
+
+    fn no_app() -> Result<Response, OxidarError> {
+        let e404 = OxidarError::http_404(Some("Could not tie to an app.".to_string()));
+        match e404.to_response() {
+            Some(response) => Ok(response),
+            None => Err(e404),
         }
+    }
 
-        let e404 = OxidarError::http_404(Some(format!("Could not tie to an app.")));
-        OxidarError::aio(
-            stream.write_all(
-                match e404.to_response() {
-                    Some(r) => r,
-                    None => return Err(e404),
-                }
-                .build_response()
-                .as_bytes(),
-            ),
-        )?;
-        OxidarError::aio(stream.flush())?;
+    fn view_panicked(&self, method: Method, uri: &str, payload: &(dyn Any + Send)) -> OxidarError {
+        self.loge(format!(
+            "A view panicked while handling {method} {uri}: {}",
+            OxidarError::panic_message(payload)
+        ));
 
-        return Ok(());
+        OxidarError::Normal(Error::Untyped(
+            "The server hit an unexpected error.".to_string(),
+        ))
+    }
+
+    /// Turns the result of a view into the response to send. Errors that can
+    /// be shown to the client are returned alongside their response so they
+    /// can still be logged.
+    fn settle(
+        result: Result<Response, OxidarError>,
+    ) -> Result<(Response, Option<OxidarError>), OxidarError> {
+        match result.and_then(Response::open) {
+            Ok(response) => Ok((response, None)),
+            Err(err) => match err.to_response() {
+                Some(response) => Ok((response, Some(err))),
+                None => Err(err),
+            },
+        }
+    }
+
+    fn connection_header(version: Version, keep_alive: bool) -> Option<&'static str> {
+        match (keep_alive, version) {
+            (false, _) => Some("close"),
+            (true, Version::Http1_0) => Some("keep-alive"),
+            (true, _) => None,
+        }
+    }
+
+    /// Adds the `Connection` header and builds the raw response, returning a
+    /// streamed body separately so it can be sent as it is produced. HTTP/1.0
+    /// clients do not understand chunked encoding, so a body of unknown length
+    /// is sent to them as is and ended by closing the connection. Also returns
+    /// whether the connection can be kept open, which it is not once a
+    /// shutdown has started while the response was being made.
+    fn finish_response(
+        &self,
+        response: Response,
+        head: bool,
+        version: Version,
+        keep_alive: bool,
+    ) -> (Vec<u8>, Option<Chunks>, bool) {
+        let chunked = version == Version::Http1_1;
+        let keep_alive = keep_alive
+            && !self.shutdown.is_shutting_down()
+            && (chunked || head || !response.has_body() || response.body_length().is_some());
+
+        let response = match Self::connection_header(version, keep_alive) {
+            Some(connection) => response.header("Connection", connection),
+            None => response,
+        };
+
+        let (raw, chunks) = response.into_raw(head, chunked);
+        (raw, chunks, keep_alive)
+    }
+
+    /// Gets the next chunk of a streamed body. An error partway through can
+    /// not be turned into an error response, so it aborts the connection.
+    fn stream_chunk(chunk: Result<Vec<u8>, OxidarError>) -> Result<Vec<u8>, OxidarError> {
+        chunk.map_err(|err| {
+            OxidarError::Abortion(Error::Untyped(format!(
+                "The response body ended early: {err}"
+            )))
+        })
     }
 
     pub fn log<T>(&self, m: T)
@@ -234,3 +700,366 @@ impl Oxidar {
         }
     }
 }
+
+/// Finds a free local port for a test server. The port is only free until
+/// something else binds it, which is unlikely in the moment before the
+/// server does.
+#[cfg(test)]
+pub(crate) fn free_addr() -> &'static str {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    Box::leak(addr.into_boxed_str())
+}
+
+/// Connects to a test server, waiting for it to start listening.
+#[cfg(test)]
+pub(crate) fn connect(addr: &str) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr) {
+            return stream;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    panic!("The test server did not start listening on {addr}.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use app::{App, ViewReg};
+    use middleware::from_fn;
+    use request::test_request;
+    use response::{IntoResponse, ResponseContent};
+    use std::sync::Mutex;
+
+    fn get(addr: &str, path: &str) -> TcpStream {
+        let mut stream = connect(addr);
+        let request = format!("GET {path} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).unwrap();
+        stream
+    }
+
+    fn read(mut stream: TcpStream) -> String {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn sheds_load_once_the_queue_is_full() {
+        let app = App::new(vec![ViewReg::get("/slow", |_, _| {
+            thread::sleep(Duration::from_millis(600));
+            "done"
+        })]);
+        let addr = free_addr();
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("/", app)],
+            addr,
+            1,
+            LogStyle::Terminal,
+            false,
+        )
+        .queue_depth(1)
+        .retry_after(Duration::from_secs(7));
+        let shutdown = oxidar.shutdown_handle();
+        let server = thread::spawn(move || oxidar.run());
+
+        // One connection keeps the worker busy and one fills the queue.
+        let busy = get(addr, "/slow");
+        thread::sleep(Duration::from_millis(200));
+        let queued = get(addr, "/slow");
+        thread::sleep(Duration::from_millis(200));
+
+        let shed = read(get(addr, "/slow"));
+        assert!(shed.starts_with("HTTP/1.1 503"), "{shed}");
+        assert!(shed.contains("Retry-After: 7\r\n"), "{shed}");
+        assert!(shed.contains("Connection: close\r\n"), "{shed}");
+
+        assert!(read(busy).ends_with("done"));
+        assert!(read(queued).ends_with("done"));
+
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn drains_requests_in_flight_on_shutdown() {
+        let app = App::new(vec![ViewReg::get("/slow", |_, _| {
+            thread::sleep(Duration::from_millis(400));
+            "done"
+        })]);
+        let addr = free_addr();
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("/", app)],
+            addr,
+            2,
+            LogStyle::Terminal,
+            false,
+        );
+        let shutdown = oxidar.shutdown_handle();
+        let server = thread::spawn(move || oxidar.run());
+
+        let mut stream = connect(addr);
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        shutdown.shutdown();
+
+        // The connection asked to be kept alive, but is closed once the
+        // request in flight is answered.
+        let response = read(stream);
+        assert!(response.contains("Connection: close\r\n"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+
+        server.join().unwrap().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    fn serve_letters(
+        max_requests: usize,
+    ) -> (
+        &'static str,
+        ShutdownHandle,
+        thread::JoinHandle<Result<(), OxidarError>>,
+    ) {
+        let app = App::new(vec![
+            ViewReg::get("/a", |_, _| "a"),
+            ViewReg::get("/b", |_, _| "b"),
+        ]);
+        let addr = free_addr();
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("/", app)],
+            addr,
+            1,
+            LogStyle::Terminal,
+            false,
+        )
+        .max_keep_alive_requests(max_requests);
+        let shutdown = oxidar.shutdown_handle();
+        (addr, shutdown, thread::spawn(move || oxidar.run()))
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order_up_to_the_limit() {
+        let (addr, shutdown, server) = serve_letters(2);
+
+        let mut stream = connect(addr);
+        stream
+            .write_all(b"GET /b HTTP/1.1\r\nHost: x\r\n\r\nGET /a HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+
+        // The second request reaches the limit, so its answer closes the
+        // connection.
+        let response = read(stream);
+        let answers: Vec<_> = response.split("HTTP/1.1 200 OK").skip(1).collect();
+        assert_eq!(answers.len(), 2, "{response}");
+        assert!(answers[0].ends_with("b"), "{response}");
+        assert!(!answers[0].contains("Connection: close"), "{response}");
+        assert!(answers[1].ends_with("a"), "{response}");
+        assert!(answers[1].contains("Connection: close\r\n"), "{response}");
+
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn keeps_http_1_0_connections_alive_on_request() {
+        let (addr, shutdown, server) = serve_letters(100);
+
+        let mut stream = connect(addr);
+        stream
+            .write_all(b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n")
+            .unwrap();
+
+        let response = read(stream);
+        let answers: Vec<_> = response.split("HTTP/1.1 200 OK").skip(1).collect();
+        assert_eq!(answers.len(), 2, "{response}");
+        assert!(
+            answers[0].contains("Connection: keep-alive\r\n"),
+            "{response}"
+        );
+        assert!(answers[0].ends_with("a"), "{response}");
+        assert!(answers[1].contains("Connection: close\r\n"), "{response}");
+        assert!(answers[1].ends_with("b"), "{response}");
+
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    fn respond(oxidar: &Oxidar, uri: &str) -> (StatusCode, String) {
+        let response = oxidar
+            .respond(test_request(Method::GET, uri, &[], b""))
+            .or_else(|err| err.to_response().ok_or(err))
+            .unwrap();
+
+        match response.content {
+            ResponseContent::Html(html) => (response.status, html),
+            _ => panic!("expected an HTML response"),
+        }
+    }
+
+    #[test]
+    fn dispatches_to_the_app_and_view_for_the_path() {
+        let blog = App::new(vec![
+            ViewReg::get("/", |_, _| "blog index"),
+            ViewReg::get("/posts/<int:id>", |_, request| {
+                format!("post {}", request.param::<u32>("id")?).into_response()
+            }),
+        ]);
+        let site = App::new(vec![
+            ViewReg::get("/", |_, _| "home"),
+            ViewReg::get("/<str:page>", |_, request| {
+                format!("page {}", request.params["page"]).into_response()
+            }),
+        ]);
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("blog", blog), AppReg::p("/", site)],
+            "127.0.0.1:0",
+            1,
+            LogStyle::Terminal,
+            false,
+        );
+
+        assert_eq!(respond(&oxidar, "/"), (StatusCode::OK, "home".to_string()));
+        assert_eq!(
+            respond(&oxidar, "/blog"),
+            (StatusCode::OK, "blog index".to_string())
+        );
+        assert_eq!(
+            respond(&oxidar, "/blog/"),
+            (StatusCode::OK, "blog index".to_string())
+        );
+        assert_eq!(
+            respond(&oxidar, "/blog/posts/5"),
+            (StatusCode::OK, "post 5".to_string())
+        );
+        assert_eq!(
+            respond(&oxidar, "/blogger"),
+            (StatusCode::OK, "page blogger".to_string())
+        );
+        assert_eq!(respond(&oxidar, "/blog/posts/x").0, StatusCode::NOT_FOUND);
+        assert_eq!(respond(&oxidar, "/a/b").0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn reads_request_bodies() {
+        let app = App::new(vec![ViewReg::post("/echo", |_, request| {
+            format!("{} bytes: {}", request.body().len(), request.text()?).into_response()
+        })]);
+        let addr = free_addr();
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("/", app)],
+            addr,
+            1,
+            LogStyle::Terminal,
+            false,
+        );
+        let shutdown = oxidar.shutdown_handle();
+        let server = thread::spawn(move || oxidar.run());
+
+        let mut stream = connect(addr);
+        stream
+            .write_all(
+                b"POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello\
+                POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\
+                Connection: close\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+            )
+            .unwrap();
+
+        let response = read(stream);
+        let bodies: Vec<&str> = response
+            .split("HTTP/1.1 ")
+            .filter_map(|response| response.split("\r\n\r\n").nth(1))
+            .collect();
+        assert_eq!(bodies, ["5 bytes: hello", "5 bytes: abcde"], "{response}");
+
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn turns_view_errors_into_responses() {
+        let app = App::new(vec![
+            ViewReg::get("/forbidden", |_, _| -> Result<Response, OxidarError> {
+                Err(OxidarError::http(StatusCode::FORBIDDEN, None))
+            }),
+            ViewReg::get("/io", |_, _| {
+                std::fs::read_to_string("/no/such/file")
+                    .map_err(|err| OxidarError::Normal(Error::Io(err)))
+            }),
+            ViewReg::get("/fatal", |_, _| -> Result<Response, OxidarError> {
+                Err(OxidarError::abort_std("broken".to_string()))
+            }),
+        ]);
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("/", app)],
+            "127.0.0.1:0",
+            1,
+            LogStyle::Terminal,
+            false,
+        );
+        let settle = |uri| Oxidar::settle(oxidar.respond(test_request(Method::GET, uri, &[], b"")));
+
+        let (response, err) = settle("/forbidden").unwrap();
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert!(err.is_some());
+
+        let (response, _) = settle("/io").unwrap();
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert!(matches!(settle("/fatal"), Err(OxidarError::Fatal(_))));
+    }
+
+    /// Middleware that notes when the request passes through it, on the way
+    /// in and on the way out.
+    fn traced(trace: &Arc<Mutex<Vec<String>>>, name: &'static str) -> impl Middleware {
+        let trace = trace.clone();
+        from_fn(move |request, next| {
+            trace.lock().unwrap().push(format!("{name} in"));
+            let response = next.run(request);
+            trace.lock().unwrap().push(format!("{name} out"));
+            response
+        })
+    }
+
+    #[test]
+    fn runs_global_middleware_around_the_app_middleware() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let view_trace = trace.clone();
+        let app = App::new(vec![
+            ViewReg::get("/", move |_, _| {
+                view_trace.lock().unwrap().push("view".to_string());
+                "home"
+            }),
+            ViewReg::get("/private", |_, _| "secret"),
+        ]);
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("/", app).middleware(traced(&trace, "app"))],
+            "127.0.0.1:0",
+            1,
+            LogStyle::Terminal,
+            false,
+        )
+        .middleware(traced(&trace, "first"))
+        .middleware(from_fn(|request, next| match request.path.as_str() {
+            "/private" => Err(OxidarError::http(StatusCode::UNAUTHORIZED, None)),
+            _ => Ok(next.run(request)?.header("X-Frame-Options", "DENY")),
+        }));
+
+        let response = oxidar
+            .respond(test_request(Method::GET, "/", &[], b""))
+            .unwrap();
+        assert_eq!(response.headers.get("X-Frame-Options"), Some("DENY"));
+        assert_eq!(
+            *trace.lock().unwrap(),
+            ["first in", "app in", "view", "app out", "first out"]
+        );
+
+        // Answering without calling `next` skips the rest of the chain.
+        trace.lock().unwrap().clear();
+        assert_eq!(respond(&oxidar, "/private").0, StatusCode::UNAUTHORIZED);
+        assert_eq!(*trace.lock().unwrap(), ["first in", "first out"]);
+    }
+}
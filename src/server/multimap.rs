@@ -0,0 +1,67 @@
+use std::str::FromStr;
+
+use crate::errors::OxidarError;
+
+/// A map that can hold more than one value for each key, in the order they
+/// were inserted. Used for form data and query strings.
+#[derive(Debug, Clone, Default)]
+pub struct MultiMap {
+    entries: Vec<(String, String)>,
+}
+
+impl MultiMap {
+    pub fn new() -> MultiMap {
+        MultiMap {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: String) {
+        self.entries.push((key, value));
+    }
+
+    /// Gets the first value for `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Gets every value for `key`.
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// Gets the first value for `key`, parsed as `T`. A missing or invalid
+    /// value results in a 400 error.
+    pub fn get_as<T: FromStr>(&self, key: &str) -> Result<T, OxidarError> {
+        let value = self.get(key).ok_or(OxidarError::http_400(Some(format!(
+            "No value given for \"{key}\"."
+        ))))?;
+
+        value.parse().or(Err(OxidarError::http_400(Some(format!(
+            "The value of \"{key}\" is not valid."
+        )))))
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
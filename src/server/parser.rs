@@ -0,0 +1,759 @@
+use std::io::{BufRead, ErrorKind};
+
+use super::http::{Headers, Method, StatusCode, Version};
+use crate::errors::{Error, OxidarError};
+
+/// Limits applied while parsing a request. Requests that go over them are
+/// answered with 413, 414 or 431 responses.
+#[derive(Debug, Clone)]
+pub struct ParserLimits {
+    /// The longest request line, in bytes.
+    pub max_request_line: usize,
+    /// The most header fields a request may have.
+    pub max_header_count: usize,
+    /// The longest header line, in bytes.
+    pub max_header_line: usize,
+    /// The largest body, in bytes.
+    pub max_body_size: usize,
+    /// The most parts a `multipart/form-data` body may have.
+    pub max_form_parts: usize,
+    /// The most files a `multipart/form-data` body may upload.
+    pub max_form_files: usize,
+}
+
+impl Default for ParserLimits {
+    fn default() -> Self {
+        ParserLimits {
+            max_request_line: 8 * 1024,
+            max_header_count: 100,
+            max_header_line: 8 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+            max_form_parts: 1000,
+            max_form_files: 100,
+        }
+    }
+}
+
+/// A request as read off the wire, before it is tied to an `Oxidar`.
+#[derive(Debug)]
+pub struct ParsedRequest {
+    pub method: Method,
+    pub uri: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+/// How many characters of a value sent by the client an error message
+/// quotes.
+const MAX_EXCERPT: usize = 32;
+
+/// How the body of a request is sent.
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+pub(crate) fn timed_out() -> OxidarError {
+    OxidarError::http(
+        StatusCode::REQUEST_TIMEOUT,
+        Some("The request was not sent in time.".to_string()),
+    )
+}
+
+pub(crate) fn io_error(err: std::io::Error) -> OxidarError {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => timed_out(),
+        _ => OxidarError::Abortion(Error::Io(err)),
+    }
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Shortens a value sent by the client so it can be quoted in an error
+/// message.
+fn excerpt(value: &str) -> String {
+    match value.char_indices().nth(MAX_EXCERPT) {
+        Some((idx, _)) => format!("{}...", &value[..idx]),
+        None => value.to_string(),
+    }
+}
+
+fn parse_request_line(line: &[u8]) -> Result<(Method, String, Version), OxidarError> {
+    let line = std::str::from_utf8(line).or(Err(OxidarError::http_400(Some(
+        "The request line is not valid UTF-8.".to_string(),
+    ))))?;
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    let (method, uri, version) = match parts[..] {
+        [method, uri, version] if !uri.is_empty() => (method, uri, version),
+        _ => {
+            return Err(OxidarError::http_400(Some(format!(
+                "Malformed request line \"{}\".",
+                excerpt(line)
+            ))))
+        }
+    };
+
+    if !is_token(method) {
+        return Err(OxidarError::http_400(Some(format!(
+            "\"{}\" is not a valid method.",
+            excerpt(method)
+        ))));
+    }
+
+    let method = Method::try_from(method).or(Err(OxidarError::http(
+        StatusCode::NOT_IMPLEMENTED,
+        Some(format!(
+            "The method \"{}\" is not supported.",
+            excerpt(method)
+        )),
+    )))?;
+
+    let version = match version.strip_prefix("HTTP/") {
+        Some(_) => Version::try_from(version).or(Err(OxidarError::http(
+            StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            None,
+        )))?,
+        None => {
+            return Err(OxidarError::http_400(Some(format!(
+                "\"{}\" is not an HTTP version.",
+                excerpt(version)
+            ))))
+        }
+    };
+
+    if !matches!(version, Version::Http1_0 | Version::Http1_1) {
+        return Err(OxidarError::http(
+            StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            None,
+        ));
+    }
+
+    Ok((method, uri.to_string(), version))
+}
+
+/// Parses one header line into `headers`, enforcing the header count limit.
+fn parse_header_line(
+    line: Vec<u8>,
+    limits: &ParserLimits,
+    headers: &mut Headers,
+) -> Result<(), OxidarError> {
+    if headers.len() >= limits.max_header_count {
+        return Err(OxidarError::http(
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Some("The request has too many headers.".to_string()),
+        ));
+    }
+
+    let line = String::from_utf8(line).or(Err(OxidarError::http_400(Some(
+        "A header is not valid UTF-8.".to_string(),
+    ))))?;
+
+    let (name, value) = line
+        .split_once(':')
+        .ok_or(OxidarError::http_400(Some(format!(
+            "Header \"{}\" could not be parsed.",
+            excerpt(&line)
+        ))))?;
+
+    // This also rejects obsolete line folding, where a line starts with
+    // whitespace, and whitespace between the name and colon.
+    if !is_token(name) {
+        return Err(OxidarError::http_400(Some(format!(
+            "\"{}\" is not a valid header name.",
+            excerpt(name)
+        ))));
+    }
+
+    headers.append(name, value.trim_matches([' ', '\t']));
+    Ok(())
+}
+
+/// Parses a chunk size line, ignoring any chunk extensions.
+fn parse_chunk_size(line: &[u8]) -> Result<usize, OxidarError> {
+    let line = String::from_utf8_lossy(line);
+
+    let size = line.split(';').next().unwrap_or_default().trim();
+
+    // `from_str_radix` also takes a leading sign, which other servers would
+    // not.
+    let is_hex = !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit());
+    match is_hex {
+        true => usize::from_str_radix(size, 16).ok(),
+        false => None,
+    }
+    .ok_or(OxidarError::http_400(Some(format!(
+        "Invalid chunk size \"{}\".",
+        excerpt(size)
+    ))))
+}
+
+/// Works out how the body is sent from the request headers, rejecting
+/// ambiguous framing and bodies over the size limit.
+fn body_length(headers: &Headers, limits: &ParserLimits) -> Result<BodyLength, OxidarError> {
+    let lengths = headers.get_all("Content-Length");
+
+    if let Some(encoding) = headers.get("Transfer-Encoding") {
+        // A request with both could be read differently by a proxy.
+        if !lengths.is_empty() {
+            return Err(OxidarError::http_400(Some(
+                "Content-Length and Transfer-Encoding can not both be sent.".to_string(),
+            )));
+        }
+
+        if headers.get_all("Transfer-Encoding").len() == 1
+            && encoding.trim().eq_ignore_ascii_case("chunked")
+        {
+            return Ok(BodyLength::Chunked);
+        }
+
+        return Err(OxidarError::http(
+            StatusCode::NOT_IMPLEMENTED,
+            Some(format!(
+                "Unsupported transfer encoding \"{}\".",
+                excerpt(encoding)
+            )),
+        ));
+    }
+
+    let length = match lengths.first() {
+        Some(length) => length,
+        None => return Ok(BodyLength::Fixed(0)),
+    };
+
+    if lengths.iter().any(|l| l != length) {
+        return Err(OxidarError::http_400(Some(
+            "Conflicting Content-Length headers.".to_string(),
+        )));
+    }
+
+    let length = match length.bytes().all(|b| b.is_ascii_digit()) {
+        true => length.parse::<usize>().ok(),
+        false => None,
+    }
+    .ok_or(OxidarError::http_400(Some(format!(
+        "Invalid content length \"{}\".",
+        excerpt(length)
+    ))))?;
+
+    if length > limits.max_body_size {
+        return Err(OxidarError::http_413(None));
+    }
+
+    Ok(BodyLength::Fixed(length))
+}
+
+/// The part of the request a `RequestParser` is reading.
+enum State {
+    RequestLine,
+    Headers,
+    /// The bytes left of a body with a fixed length.
+    Body(usize),
+    ChunkSize,
+    /// The bytes left of the current chunk.
+    ChunkData(usize),
+    /// The CRLF after a chunk's data.
+    ChunkEnd,
+    Trailers,
+}
+
+/// A request parser that is fed bytes as they arrive, so the blocking and
+/// async servers read requests the same way, whatever the size of each read.
+///
+/// ```ignore
+/// let mut parser = RequestParser::new(&limits);
+/// loop {
+///     let buf = reader.fill_buf()?;
+///     if buf.is_empty() {
+///         return parser.finish();
+///     }
+///     let (used, request) = parser.feed(buf)?;
+///     reader.consume(used);
+///     if request.is_some() {
+///         return Ok(request);
+///     }
+/// }
+/// ```
+pub struct RequestParser<'a> {
+    limits: &'a ParserLimits,
+    state: State,
+    /// The line read so far, or the CRLF after a chunk.
+    line: Vec<u8>,
+    request: Option<ParsedRequest>,
+    trailers: Headers,
+}
+
+impl<'a> RequestParser<'a> {
+    pub fn new(limits: &'a ParserLimits) -> RequestParser<'a> {
+        RequestParser {
+            limits,
+            state: State::RequestLine,
+            line: Vec::new(),
+            request: None,
+            trailers: Headers::new(),
+        }
+    }
+
+    /// Parses as much of `buf` as belongs to the request. Returns how many
+    /// bytes were used, and the request once it is complete. Bytes after the
+    /// request are left unused, so pipelined requests can follow.
+    pub fn feed(&mut self, buf: &[u8]) -> Result<(usize, Option<ParsedRequest>), OxidarError> {
+        let mut used = 0;
+
+        while used < buf.len() {
+            let rest = &buf[used..];
+
+            used += match self.state {
+                State::RequestLine | State::Headers | State::ChunkSize | State::Trailers => {
+                    let (taken, line) = self.take_line(rest)?;
+                    if let Some(line) = line {
+                        self.on_line(line)?;
+                    }
+                    taken
+                }
+                State::Body(remaining) => {
+                    let taken = remaining.min(rest.len());
+                    self.body().extend_from_slice(&rest[..taken]);
+                    self.state = State::Body(remaining - taken);
+                    taken
+                }
+                State::ChunkData(remaining) => {
+                    let taken = remaining.min(rest.len());
+                    self.body().extend_from_slice(&rest[..taken]);
+                    self.state = match remaining - taken {
+                        0 => State::ChunkEnd,
+                        remaining => State::ChunkData(remaining),
+                    };
+                    taken
+                }
+                State::ChunkEnd => {
+                    let taken = (2 - self.line.len()).min(rest.len());
+                    self.line.extend_from_slice(&rest[..taken]);
+
+                    if self.line.len() == 2 {
+                        if self.line != b"\r\n" {
+                            return Err(OxidarError::http_400(Some(
+                                "Chunk data was not followed by CRLF.".to_string(),
+                            )));
+                        }
+                        self.line.clear();
+                        self.state = State::ChunkSize;
+                    }
+                    taken
+                }
+            };
+
+            if let State::Body(0) = self.state {
+                return Ok((used, self.request.take()));
+            }
+        }
+
+        Ok((used, None))
+    }
+
+    /// Called when the input ends. Returns `None` if it ended before a
+    /// request started, and an error if it ended in the middle of one.
+    pub fn finish(self) -> Result<Option<ParsedRequest>, OxidarError> {
+        let message = match self.state {
+            State::RequestLine | State::Headers | State::ChunkSize | State::Trailers
+                if !self.line.is_empty() =>
+            {
+                "The request ended in the middle of a line."
+            }
+            State::RequestLine => return Ok(None),
+            State::Headers => "The request ended before its headers did.",
+            _ => "The request ended before its body did.",
+        };
+
+        Err(OxidarError::http_400(Some(message.to_string())))
+    }
+
+    /// Gathers bytes up to the end of a line. Returns how many bytes were
+    /// taken, and the line without its line ending once it is complete.
+    fn take_line(&mut self, buf: &[u8]) -> Result<(usize, Option<Vec<u8>>), OxidarError> {
+        let (max, too_long) = match self.state {
+            State::RequestLine => (self.limits.max_request_line, StatusCode::URI_TOO_LONG),
+            State::ChunkSize => (self.limits.max_header_line, StatusCode::BAD_REQUEST),
+            _ => (
+                self.limits.max_header_line,
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ),
+        };
+
+        let (taken, done) = match buf.iter().position(|b| *b == b'\n') {
+            Some(idx) => (idx + 1, true),
+            None => (buf.len(), false),
+        };
+
+        self.line.extend_from_slice(&buf[..taken]);
+        if self.line.len() > max + 2 {
+            return Err(OxidarError::http(too_long, None));
+        }
+
+        if !done {
+            return Ok((taken, None));
+        }
+
+        let mut line = std::mem::take(&mut self.line);
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        // Some servers end lines at a bare CR, so one could hide a header
+        // from them.
+        if line.contains(&b'\r') {
+            return Err(OxidarError::http_400(Some(
+                "A line of the request contains a bare CR.".to_string(),
+            )));
+        }
+
+        Ok((taken, Some(line)))
+    }
+
+    fn on_line(&mut self, line: Vec<u8>) -> Result<(), OxidarError> {
+        match self.state {
+            // Empty lines before a request are ignored.
+            State::RequestLine if line.is_empty() => {}
+            State::RequestLine => {
+                let (method, uri, version) = parse_request_line(&line)?;
+                self.request = Some(ParsedRequest {
+                    method,
+                    uri,
+                    version,
+                    headers: Headers::new(),
+                    body: Vec::new(),
+                });
+                self.state = State::Headers;
+            }
+            State::Headers if line.is_empty() => self.end_head()?,
+            State::Headers => {
+                let request = self.request.as_mut().expect("The request line was parsed.");
+                parse_header_line(line, self.limits, &mut request.headers)?;
+            }
+            State::ChunkSize => {
+                let size = parse_chunk_size(&line)?;
+                if self.body().len().saturating_add(size) > self.limits.max_body_size {
+                    return Err(OxidarError::http_413(None));
+                }
+
+                self.state = match size {
+                    0 => State::Trailers,
+                    size => State::ChunkData(size),
+                };
+            }
+            // Trailer fields are checked and dropped. An empty body left to
+            // read marks the request as complete.
+            State::Trailers if line.is_empty() => self.state = State::Body(0),
+            State::Trailers => parse_header_line(line, self.limits, &mut self.trailers)?,
+            State::Body(_) | State::ChunkData(_) | State::ChunkEnd => {
+                unreachable!("Bodies are not read by line.")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the headers once they have all been read, and works out how
+    /// the body is sent.
+    fn end_head(&mut self) -> Result<(), OxidarError> {
+        let request = self.request.as_mut().expect("The request line was parsed.");
+
+        if let Version::Http1_1 = request.version {
+            if request.headers.get_all("Host").len() != 1 {
+                return Err(OxidarError::http_400(Some(
+                    "HTTP/1.1 requests must have exactly one Host header.".to_string(),
+                )));
+            }
+        }
+
+        self.state = match body_length(&request.headers, self.limits)? {
+            BodyLength::Chunked => State::ChunkSize,
+            BodyLength::Fixed(length) => {
+                request.body.reserve_exact(length);
+                State::Body(length)
+            }
+        };
+
+        Ok(())
+    }
+
+    fn body(&mut self) -> &mut Vec<u8> {
+        &mut self
+            .request
+            .as_mut()
+            .expect("The request line was parsed.")
+            .body
+    }
+}
+
+/// Parses one HTTP/1.x request from `reader`, leaving any bytes after it
+/// unread so pipelined requests can follow. Returns `None` if the input ends
+/// before a request starts.
+///
+/// Every malformed request results in an `OxidarError::Normal` error with a
+/// 4xx or 5xx status, never a panic.
+pub fn parse_request<R: BufRead>(
+    reader: &mut R,
+    limits: &ParserLimits,
+) -> Result<Option<ParsedRequest>, OxidarError> {
+    let mut parser = RequestParser::new(limits);
+
+    loop {
+        let buf = reader.fill_buf().map_err(io_error)?;
+        if buf.is_empty() {
+            return parser.finish();
+        }
+
+        let (used, request) = parser.feed(buf)?;
+        reader.consume(used);
+
+        if request.is_some() {
+            return Ok(request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+    use crate::server::response::ResponseContent;
+
+    fn parse(raw: &[u8]) -> Result<Option<ParsedRequest>, OxidarError> {
+        parse_request(&mut &raw[..], &ParserLimits::default())
+    }
+
+    fn status(raw: &[u8], limits: &ParserLimits) -> u16 {
+        match parse_request(&mut &raw[..], limits) {
+            Err(OxidarError::Normal(err)) => err.status().as_u16(),
+            Err(err) => panic!("Not an HTTP error: {err}"),
+            Ok(request) => panic!("Parsed a bad request: {request:?}"),
+        }
+    }
+
+    fn rejected(raw: &[u8]) -> u16 {
+        status(raw, &ParserLimits::default())
+    }
+
+    fn small_limits() -> ParserLimits {
+        ParserLimits {
+            max_request_line: 32,
+            max_header_count: 3,
+            max_header_line: 32,
+            max_body_size: 10,
+            ..ParserLimits::default()
+        }
+    }
+
+    #[test]
+    fn parses_requests() {
+        let request = parse(b"POST /a?b=c HTTP/1.1\r\nHost: x\r\nX-Name:  value \t\r\nContent-Length: 5\r\n\r\nhello")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.uri, "/a?b=c");
+        assert_eq!(request.version, Version::Http1_1);
+        assert_eq!(request.headers.get("x-name"), Some("value"));
+        assert_eq!(request.body, b"hello");
+
+        // HTTP/1.0 does not need a Host header, and bare LF ends lines.
+        let request = parse(b"\r\n\nGET / HTTP/1.0\nAccept: */*\n\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.version, Version::Http1_0);
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn parses_chunked_bodies() {
+        let raw = b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: Chunked\r\n\r\n\
+            5;name=value\r\nhello\r\nA \r\n, chunked!\r\n0\r\nX-Trailer: a\r\n\r\n";
+        let request = parse(raw).unwrap().unwrap();
+
+        assert_eq!(request.body, b"hello, chunked!");
+        assert!(!request.headers.contains("X-Trailer"));
+    }
+
+    #[test]
+    fn reads_requests_fed_a_byte_at_a_time() {
+        let raw = b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3\r\nabc\r\n0\r\n\r\nGET /next HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nhi";
+        let mut reader = BufReader::with_capacity(1, &raw[..]);
+        let limits = ParserLimits::default();
+
+        let first = parse_request(&mut reader, &limits).unwrap().unwrap();
+        assert_eq!(first.body, b"abc");
+
+        let second = parse_request(&mut reader, &limits).unwrap().unwrap();
+        assert_eq!(second.uri, "/next");
+        assert_eq!(second.body, b"hi");
+
+        assert!(parse_request(&mut reader, &limits).unwrap().is_none());
+    }
+
+    #[test]
+    fn leaves_pipelined_requests_unread() {
+        let raw = b"GET /1 HTTP/1.1\r\nHost: x\r\n\r\nGET /2 HTTP/1.1\r\nHost: x\r\n\r\n";
+        let limits = ParserLimits::default();
+        let mut parser = RequestParser::new(&limits);
+
+        let (used, request) = parser.feed(raw).unwrap();
+        assert_eq!(request.unwrap().uri, "/1");
+        assert_eq!(&raw[used..], b"GET /2 HTTP/1.1\r\nHost: x\r\n\r\n");
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = small_limits();
+        let long = "a".repeat(40);
+
+        let raw = format!("GET /{long} HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(status(raw.as_bytes(), &limits), 414);
+
+        let raw = format!("GET / HTTP/1.1\r\nHost: x\r\nX-Long: {long}\r\n\r\n");
+        assert_eq!(status(raw.as_bytes(), &limits), 431);
+
+        let raw = b"GET / HTTP/1.1\r\nHost: x\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert_eq!(status(raw, &limits), 431);
+
+        let raw = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 11\r\n\r\n";
+        assert_eq!(status(raw, &limits), 413);
+
+        let raw = b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+            6\r\nabcdef\r\n6\r\nabcdef\r\n0\r\n\r\n";
+        assert_eq!(status(raw, &limits), 413);
+
+        let raw =
+            format!("POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n1;{long}\r\n");
+        assert_eq!(status(raw.as_bytes(), &limits), 400);
+
+        let raw = format!(
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX: {long}\r\n"
+        );
+        assert_eq!(status(raw.as_bytes(), &limits), 431);
+    }
+
+    #[test]
+    fn rejects_ambiguous_framing() {
+        let head = "POST / HTTP/1.1\r\nHost: x\r\n";
+
+        let raw = format!("{head}Content-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert_eq!(rejected(raw.as_bytes()), 400);
+
+        let raw = format!("{head}Transfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n");
+        assert_eq!(rejected(raw.as_bytes()), 400);
+
+        let raw = format!("{head}Content-Length: 3\r\nContent-Length: 4\r\n\r\nabcd");
+        assert_eq!(rejected(raw.as_bytes()), 400);
+
+        for length in [
+            "+3",
+            "-3",
+            "3 3",
+            "3,3",
+            "0x3",
+            "",
+            "99999999999999999999999",
+        ] {
+            let raw = format!("{head}Content-Length: {length}\r\n\r\nabc");
+            assert_eq!(rejected(raw.as_bytes()), 400, "{length}");
+        }
+
+        let raw = format!("{head}Transfer-Encoding: gzip, chunked\r\n\r\n");
+        assert_eq!(rejected(raw.as_bytes()), 501);
+
+        let raw = format!("{head}Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert_eq!(rejected(raw.as_bytes()), 501);
+
+        // The same length sent twice is not ambiguous.
+        let raw = format!("{head}Content-Length: 3\r\nContent-Length: 3\r\n\r\nabc");
+        assert_eq!(parse(raw.as_bytes()).unwrap().unwrap().body, b"abc");
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+        let head = "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        for size in [
+            "+5",
+            "-5",
+            "0x5",
+            "",
+            " ",
+            "g",
+            "5 5",
+            "fffffffffffffffffffff",
+        ] {
+            let raw = format!("{head}{size}\r\nhello\r\n0\r\n\r\n");
+            assert_eq!(rejected(raw.as_bytes()), 400, "{size:?}");
+        }
+
+        let raw = format!("{head}5\r\nhelloXX0\r\n\r\n");
+        assert_eq!(rejected(raw.as_bytes()), 400);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases: [(&[u8], u16); 16] = [
+            (b"GET /\r\n\r\n", 400),
+            (b"GET  / HTTP/1.1\r\nHost: x\r\n\r\n", 400),
+            (b"GET / HTTP/1.1 extra\r\nHost: x\r\n\r\n", 400),
+            (b"GE(T / HTTP/1.1\r\nHost: x\r\n\r\n", 400),
+            (b"BREW / HTTP/1.1\r\nHost: x\r\n\r\n", 501),
+            (b"GET / FOO/1.1\r\nHost: x\r\n\r\n", 400),
+            (b"GET / HTTP/2.0\r\nHost: x\r\n\r\n", 505),
+            (b"GET / HTTP/1.1\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nHost: x\r\nHost: y\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nHost: x\r\nNo colon\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nHost: x\r\nName : value\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nHost: x\r\nA: 1\r\n folded\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nHost: x\r\nA: \xff\r\n\r\n", 400),
+            (b"GET /\xff HTTP/1.1\r\nHost: x\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nHost: x\r\nA: 1\rB: 2\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\rHost: x\r\n\r\n", 400),
+        ];
+
+        for (raw, expected) in cases {
+            assert_eq!(rejected(raw), expected, "{}", String::from_utf8_lossy(raw));
+        }
+    }
+
+    #[test]
+    fn reports_truncated_requests() {
+        assert!(parse(b"").unwrap().is_none());
+        assert!(parse(b"\r\n").unwrap().is_none());
+
+        assert_eq!(rejected(b"GET / HT"), 400);
+        assert_eq!(rejected(b"GET / HTTP/1.1\r\nHost: x\r\n"), 400);
+        assert_eq!(
+            rejected(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhel"),
+            400
+        );
+
+        let raw = b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n";
+        assert_eq!(rejected(raw), 400);
+    }
+
+    #[test]
+    fn shortens_values_quoted_in_errors() {
+        let method = "X".repeat(1000);
+        let raw = format!("{method} / HTTP/1.1\r\nHost: x\r\n\r\n");
+        let err = parse(raw.as_bytes()).unwrap_err().to_string();
+        assert!(err.ends_with(&format!("\"{}...\" is not supported.", "X".repeat(32))));
+
+        let raw = "GET / HTTP/1.1\r\nHost: x\r\n<script>alert(1)</script>: y\r\n\r\n";
+        let response = parse(raw.as_bytes()).unwrap_err().to_response().unwrap();
+        match response.content {
+            ResponseContent::Html(html) => assert!(html.contains("&lt;script&gt;alert(1)")),
+            _ => panic!("expected an HTML error page"),
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use super::encoding::encode_path_segment;
+
+/// The converters that may be used inside of a view path, in the form
+/// `<converter:name>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Converter {
+    Int,
+    Str,
+    Slug,
+    Uuid,
+    Path,
+}
+
+impl TryFrom<&str> for Converter {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "int" => Ok(Self::Int),
+            "str" => Ok(Self::Str),
+            "slug" => Ok(Self::Slug),
+            "uuid" => Ok(Self::Uuid),
+            "path" => Ok(Self::Path),
+            _ => Err(format!("Unknown path converter \"{value}\".")),
+        }
+    }
+}
+
+impl Converter {
+    fn accepts(&self, segment: &str) -> bool {
+        if segment.is_empty() {
+            return false;
+        }
+
+        match self {
+            Converter::Int => segment.chars().all(|c| c.is_ascii_digit()),
+            Converter::Str | Converter::Path => true,
+            Converter::Slug => segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            Converter::Uuid => {
+                segment.len() == 36
+                    && segment.char_indices().all(|(i, c)| match i {
+                        8 | 13 | 18 | 23 => c == '-',
+                        _ => c.is_ascii_digit() || ('a'..='f').contains(&c),
+                    })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Segment {
+    Literal(String),
+    Param(String, Converter),
+}
+
+/// A parsed view path such as `/posts/<int:id>/<slug:title>`.
+///
+/// Parameters take up a whole path segment. A parameter with no converter,
+/// `<name>`, uses the `str` converter.
+#[derive(Debug, Clone)]
+pub struct PathPattern {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+impl PathPattern {
+    pub fn parse(path: &str) -> Result<PathPattern, String> {
+        let path = path.trim_matches('/');
+        let mut segments = Vec::new();
+
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            let param = match segment.strip_prefix('<') {
+                Some(param) => param.strip_suffix('>').ok_or(format!(
+                    "Path segment \"{segment}\" in \"{path}\" is missing a closing \">\"."
+                ))?,
+                None => {
+                    segments.push(Segment::Literal(segment.to_string()));
+                    continue;
+                }
+            };
+
+            let (converter, name) = match param.split_once(':') {
+                Some((converter, name)) => (Converter::try_from(converter)?, name),
+                None => (Converter::Str, param),
+            };
+
+            if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return Err(format!(
+                    "\"{name}\" in \"{path}\" is not a valid path parameter name."
+                ));
+            }
+
+            segments.push(Segment::Param(name.to_string(), converter));
+        }
+
+        Ok(PathPattern {
+            raw: format!("/{path}"),
+            segments,
+        })
+    }
+
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Rebuilds a path from the pattern, filling each parameter with the
+    /// value given for it in `args`, percent-encoded.
+    pub fn build(&self, args: &[(&str, String)]) -> Result<String, String> {
+        let mut parts = Vec::with_capacity(self.segments.len());
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => parts.push(literal.clone()),
+                Segment::Param(name, converter) => {
+                    let value = match args.iter().find(|(arg, _)| arg == name) {
+                        Some((_, value)) => value,
+                        None => return Err(format!("No value given for \"{name}\".")),
+                    };
+
+                    let valid = match converter {
+                        Converter::Path => value.split('/').all(|s| converter.accepts(s)),
+                        _ => converter.accepts(value) && !value.contains('/'),
+                    };
+
+                    if !valid {
+                        return Err(format!("\"{value}\" is not a valid value for \"{name}\"."));
+                    }
+
+                    let encoded: Vec<String> = match converter {
+                        Converter::Path => value.split('/').map(encode_path_segment).collect(),
+                        _ => vec![encode_path_segment(value)],
+                    };
+                    parts.push(encoded.join("/"));
+                }
+            }
+        }
+
+        if let Some((arg, _)) = args.iter().find(|(arg, _)| {
+            !self
+                .segments
+                .iter()
+                .any(|s| matches!(s, Segment::Param(name, _) if name == arg))
+        }) {
+            return Err(format!(
+                "\"{}\" has no parameter named \"{arg}\".",
+                self.raw
+            ));
+        }
+
+        Ok(parts.join("/"))
+    }
+
+    /// The names of the pattern's parameters, in order.
+    pub fn param_names(&self) -> Vec<&str> {
+        self.segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Param(name, _) => Some(name.as_str()),
+                Segment::Literal(_) => None,
+            })
+            .collect()
+    }
+
+    /// Matches a path against the pattern, returning the captured parameters
+    /// if every segment is accepted by its converter.
+    pub fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = path
+            .trim_matches('/')
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let mut params = HashMap::new();
+        match Self::match_segments(&self.segments, &parts, &mut params) {
+            true => Some(params),
+            false => None,
+        }
+    }
+
+    fn match_segments(
+        segments: &[Segment],
+        parts: &[&str],
+        params: &mut HashMap<String, String>,
+    ) -> bool {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return parts.is_empty(),
+        };
+
+        match segment {
+            Segment::Literal(literal) => {
+                parts.first() == Some(&literal.as_str())
+                    && Self::match_segments(rest, &parts[1..], params)
+            }
+            Segment::Param(name, Converter::Path) => {
+                for taken in (1..=parts.len()).rev() {
+                    if Self::match_segments(rest, &parts[taken..], params) {
+                        params.insert(name.clone(), parts[..taken].join("/"));
+                        return true;
+                    }
+                }
+
+                false
+            }
+            Segment::Param(name, converter) => match parts.first() {
+                Some(part) if converter.accepts(part) => {
+                    if Self::match_segments(rest, &parts[1..], params) {
+                        params.insert(name.clone(), part.to_string());
+                        return true;
+                    }
+
+                    false
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::encoding::decode_path;
+
+    fn build(pattern: &str, args: &[(&str, &str)]) -> Result<String, String> {
+        let args: Vec<(&str, String)> = args.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        PathPattern::parse(pattern).unwrap().build(&args)
+    }
+
+    #[test]
+    fn matches_typed_parameters() {
+        let pattern = PathPattern::parse("/posts/<int:id>/<slug:title>").unwrap();
+        let params = pattern.matches("/posts/5/hello-world").unwrap();
+        assert_eq!(params["id"], "5");
+        assert_eq!(params["title"], "hello-world");
+
+        assert!(pattern.matches("/posts/five/hello").is_none());
+        assert!(pattern.matches("posts/5").is_none());
+        assert!(pattern.matches("/posts/5/hello/extra").is_none());
+    }
+
+    #[test]
+    fn path_parameters_match_several_segments() {
+        let pattern = PathPattern::parse("/files/<path:file>").unwrap();
+        assert_eq!(
+            pattern.matches("/files/a/b/c.txt").unwrap()["file"],
+            "a/b/c.txt"
+        );
+        assert!(pattern.matches("/files").is_none());
+    }
+
+    #[test]
+    fn builds_paths() {
+        assert_eq!(build("/posts/<int:id>", &[("id", "5")]).unwrap(), "posts/5");
+        assert!(build("/posts/<int:id>", &[("id", "x")]).is_err());
+        assert!(build("/posts/<int:id>", &[]).is_err());
+        assert!(build("/posts/<int:id>", &[("id", "5"), ("other", "1")]).is_err());
+        assert!(build("/search/<str:q>", &[("q", "a/b")]).is_err());
+    }
+
+    #[test]
+    fn percent_encodes_built_values() {
+        assert_eq!(
+            build("/search/<str:q>", &[("q", "a b?c#d%")]).unwrap(),
+            "search/a%20b%3Fc%23d%25"
+        );
+        assert_eq!(
+            build("/files/<path:file>", &[("file", "my docs/é?.txt")]).unwrap(),
+            "files/my%20docs/%C3%A9%3F.txt"
+        );
+    }
+
+    #[test]
+    fn built_values_match_again() {
+        let pattern = PathPattern::parse("/search/<str:q>").unwrap();
+        let path = pattern.build(&[("q", "a b?c#d".to_string())]).unwrap();
+        assert_eq!(
+            pattern.matches(&decode_path(&path).unwrap()).unwrap()["q"],
+            "a b?c#d"
+        );
+    }
+}
@@ -1,9 +1,298 @@
-use super::http::{Method, Version};
-use std::collections::HashMap;
+use super::{
+    cookie::{parse_cookies, Key},
+    encoding::{decode_path, parse_urlencoded},
+    form::Form,
+    http::{Headers, Method, Version},
+    multimap::MultiMap,
+    parser::{ParsedRequest, ParserLimits},
+    state::State,
+    urls::Urls,
+};
+use crate::errors::{Error, OxidarError};
+use serde::de::DeserializeOwned;
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
 
 pub struct Request {
     pub method: Method,
     pub uri: String,
+    /// The percent-decoded path of the uri, used for routing.
+    pub path: String,
+    pub(crate) query: MultiMap,
     pub version: Version,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    pub params: HashMap<String, String>,
+    pub urls: Arc<Urls>,
+    pub state: Arc<State>,
+    form: OnceLock<Form>,
+    limits: ParserLimits,
+}
+
+impl Request {
+    /// Builds the request from its parsed head and body. A path holding an
+    /// encoded slash results in a 400 error.
+    pub(crate) fn new(
+        parsed: ParsedRequest,
+        urls: Arc<Urls>,
+        state: Arc<State>,
+        limits: &ParserLimits,
+    ) -> Result<Request, OxidarError> {
+        let uri_without_fragment = parsed.uri.split('#').next().unwrap_or_default();
+        let (path, query) = uri_without_fragment
+            .split_once('?')
+            .unwrap_or((uri_without_fragment, ""));
+
+        let path = decode_path(path).ok_or(OxidarError::http_400(Some(
+            "Encoded slashes are not allowed in the path.".to_string(),
+        )))?;
+
+        Ok(Request {
+            path,
+            query: parse_urlencoded(query),
+            method: parsed.method,
+            uri: parsed.uri,
+            version: parsed.version,
+            headers: parsed.headers,
+            body: parsed.body,
+            params: HashMap::new(),
+            urls,
+            state,
+            form: OnceLock::new(),
+            limits: limits.clone(),
+        })
+    }
+
+    /// Gets the first value of a header, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The raw bytes of the request body.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The request body as UTF-8 text. Invalid UTF-8 results in a 400 error.
+    pub fn text(&self) -> Result<&str, OxidarError> {
+        std::str::from_utf8(&self.body).or(Err(OxidarError::http_400(Some(
+            "The request body is not valid UTF-8.".to_string(),
+        ))))
+    }
+
+    /// Deserializes the request body as JSON. Invalid JSON results in a 400
+    /// error.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, OxidarError> {
+        serde_json::from_slice(&self.body).map_err(|err| {
+            OxidarError::http_400(Some(format!("The request body is not valid JSON: {err}")))
+        })
+    }
+
+    /// The parsed query string of the uri.
+    pub fn query(&self) -> &MultiMap {
+        &self.query
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` or `multipart/form-data`
+    /// body. The body is decoded on the first call, which writes any uploaded
+    /// files to a temporary directory, and later calls return the same form.
+    pub fn form(&self) -> Result<&Form, OxidarError> {
+        if let Some(form) = self.form.get() {
+            return Ok(form);
+        }
+
+        let form = Form::parse(self.header("Content-Type"), &self.body, &self.limits)?;
+        Ok(self.form.get_or_init(|| form))
+    }
+
+    /// Gets a parameter captured from the view path, parsed as `T`.
+    ///
+    /// A value that can not be parsed results in a 404 error. The value is
+    /// left out of the error, since it comes from the client.
+    pub fn param<T: FromStr>(&self, name: &str) -> Result<T, OxidarError> {
+        let value = self
+            .params
+            .get(name)
+            .ok_or(OxidarError::Normal(Error::Untyped(format!(
+                "The view path has no parameter named \"{name}\"."
+            ))))?;
+
+        value.parse().or(Err(OxidarError::http_404(Some(format!(
+            "The value of \"{name}\" is not valid."
+        )))))
+    }
+
+    /// Gets the state of type `T` registered with `Oxidar::with_state`.
+    pub fn state<T: Any + Send + Sync>(&self) -> Result<&T, OxidarError> {
+        self.state.get()
+    }
+
+    /// The cookies sent with the request, from every `Cookie` header, with
+    /// their values percent-decoded.
+    pub fn cookies(&self) -> HashMap<String, String> {
+        parse_cookies(self.headers.get_all("Cookie"))
+    }
+
+    /// Gets the value of a cookie.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+
+    /// The key set with `Oxidar::secret_key`. A missing key results in a 500
+    /// error.
+    pub fn key(&self) -> Result<&Key, OxidarError> {
+        self.state.get().or(Err(OxidarError::Normal(Error::Untyped(
+            "No secret key has been set, use Oxidar::secret_key to set one.".to_string(),
+        ))))
+    }
+
+    /// Gets the value of a cookie made with `Cookie::signed`. A cookie whose
+    /// signature does not match is treated as missing.
+    pub fn signed_cookie(&self, name: &str) -> Result<Option<String>, OxidarError> {
+        let key = self.key()?;
+        Ok(self.cookie(name).and_then(|value| key.verify(name, &value)))
+    }
+
+    /// Gets the value of a cookie made with `Cookie::encrypted`. A cookie that
+    /// can not be decrypted is treated as missing.
+    pub fn private_cookie(&self, name: &str) -> Result<Option<String>, OxidarError> {
+        let key = self.key()?;
+        Ok(self
+            .cookie(name)
+            .and_then(|value| key.decrypt(name, &value)))
+    }
+
+    /// Builds the full url of a named view. See `Oxidar::reverse`.
+    pub fn reverse<T: Display>(
+        &self,
+        name: &str,
+        args: &[(&str, T)],
+    ) -> Result<String, OxidarError> {
+        self.urls.reverse(name, args)
+    }
+}
+
+/// Builds a request the way the server does once it has been parsed.
+#[cfg(test)]
+pub(crate) fn test_request(
+    method: Method,
+    uri: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Request {
+    let mut parsed_headers = Headers::new();
+    for (name, value) in headers {
+        parsed_headers.append(name, value);
+    }
+
+    let parsed = ParsedRequest {
+        method,
+        uri: uri.to_string(),
+        version: Version::Http1_1,
+        headers: parsed_headers,
+        body: body.to_vec(),
+    };
+
+    Request::new(
+        parsed,
+        Arc::new(Urls::new(&[])),
+        Arc::new(State::new()),
+        &ParserLimits::default(),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_path_parameters() {
+        let mut request = test_request(Method::GET, "/posts/5/%3Cb%3E", &[], b"");
+        request.params.insert("id".to_string(), "5".to_string());
+        request
+            .params
+            .insert("title".to_string(), "<b>".to_string());
+
+        assert_eq!(request.param::<u32>("id").unwrap(), 5);
+        assert_eq!(request.param::<String>("title").unwrap(), "<b>");
+        assert_eq!(
+            request.param::<u32>("title").unwrap_err().to_string(),
+            "Normal Error (404) The value of \"title\" is not valid."
+        );
+        assert!(matches!(
+            request.param::<u32>("missing"),
+            Err(OxidarError::Normal(Error::Untyped(_)))
+        ));
+    }
+
+    #[test]
+    fn parses_the_query_string() {
+        let request = test_request(
+            Method::GET,
+            "/search?q=a+b%21&tag=x&tag=y&n=7#top",
+            &[],
+            b"",
+        );
+        assert_eq!(request.path, "/search");
+        assert_eq!(request.query().get("q"), Some("a b!"));
+        assert_eq!(request.query().get_all("tag"), vec!["x", "y"]);
+        assert_eq!(request.query().get_as::<u8>("n").unwrap(), 7);
+        assert_eq!(
+            request.query().get_as::<u8>("q").unwrap_err().to_string(),
+            "Normal Error (400) The value of \"q\" is not valid."
+        );
+        assert!(request.query().get_as::<u8>("missing").is_err());
+    }
+
+    #[test]
+    fn decodes_the_path() {
+        assert_eq!(
+            test_request(Method::GET, "/a%20b/%C3%A9", &[], b"").path,
+            "/a b/é"
+        );
+        assert_eq!(
+            test_request(Method::GET, "/a%252Fb", &[], b"").path,
+            "/a%2Fb"
+        );
+
+        for uri in ["/a%2Fb", "/a%2fb"] {
+            let parsed = ParsedRequest {
+                method: Method::GET,
+                uri: uri.to_string(),
+                version: Version::Http1_1,
+                headers: Headers::new(),
+                body: Vec::new(),
+            };
+            let request = Request::new(
+                parsed,
+                Arc::new(Urls::new(&[])),
+                Arc::new(State::new()),
+                &ParserLimits::default(),
+            );
+            assert_eq!(
+                request.err().unwrap().to_string(),
+                "Normal Error (400) Encoded slashes are not allowed in the path."
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_text_and_json_bodies() {
+        let request = test_request(Method::POST, "/", &[], br#"{"id": 5}"#);
+        assert_eq!(request.text().unwrap(), r#"{"id": 5}"#);
+        assert_eq!(request.json::<HashMap<String, u32>>().unwrap()["id"], 5);
+
+        let request = test_request(Method::POST, "/", &[], b"\xff");
+        assert_eq!(
+            request.text().unwrap_err().to_string(),
+            "Normal Error (400) The request body is not valid UTF-8."
+        );
+        assert!(request.json::<HashMap<String, u32>>().is_err());
+    }
 }
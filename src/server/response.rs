@@ -1,35 +1,650 @@
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Take},
+    iter,
+    path::PathBuf,
+};
+
+use super::{
+    cookie::Cookie,
+    http::{Headers, StatusCode, Version},
+    mime,
+};
 use crate::errors::{Error, OxidarError};
 
-use super::http::Version;
+/// The chunks of a streamed body, produced as they are sent. An error ends
+/// the response early, and as the head has already gone out the client only
+/// sees the body cut short.
+pub type Chunks = Box<dyn Iterator<Item = Result<Vec<u8>, OxidarError>> + Send>;
+
+/// The size of the chunks read by `ResponseContent::reader`.
+const READ_CHUNK_SIZE: usize = 16 * 1024;
 
 pub enum ResponseContent {
     Json(String),
     Html(String),
+    Text(String),
+    Bytes {
+        content_type: String,
+        data: Vec<u8>,
+    },
+    /// A file streamed from disk, with its type guessed from its extension.
+    /// A file that can not be found gets a 404 response.
+    File(PathBuf),
+    /// Redirects to `location`, with `301 Moved Permanently` if `permanent` is
+    /// set and `302 Found` otherwise.
+    Redirect {
+        location: String,
+        permanent: bool,
+    },
+    /// No body at all.
+    Empty,
+    /// A body that is sent chunk by chunk as it is produced, rather than
+    /// built in memory first. HTTP/1.1 clients get it with chunked transfer
+    /// encoding, unless a `Content-Length` header is set.
+    Stream {
+        content_type: String,
+        chunks: Chunks,
+    },
 }
 
 impl ResponseContent {
-    pub(crate) fn get_body(&self) -> String {
+    /// Streams the chunks produced by `chunks`.
+    ///
+    /// ```ignore
+    /// let rows = db.rows().map(|row| Ok(format!("{},{}\n", row.id, row.name).into_bytes()));
+    /// ResponseContent::stream("text/csv", rows)
+    /// ```
+    pub fn stream<I>(content_type: &str, chunks: I) -> ResponseContent
+    where
+        I: Iterator<Item = Result<Vec<u8>, OxidarError>> + Send + 'static,
+    {
+        ResponseContent::Stream {
+            content_type: content_type.to_string(),
+            chunks: Box::new(chunks),
+        }
+    }
+
+    /// Streams everything read from `reader`, such as a file or the output
+    /// of a child process.
+    pub fn reader<R: Read + Send + 'static>(content_type: &str, reader: R) -> ResponseContent {
+        ResponseContent::stream(content_type, ReadChunks(Some(reader)))
+    }
+
+    /// The content type the body is sent with unless one is set, or `None`
+    /// for content without a body.
+    pub(crate) fn content_type(&self) -> Option<&str> {
+        match self {
+            ResponseContent::Json(_) => Some("application/json"),
+            ResponseContent::Html(_) => Some("text/html; charset=utf-8"),
+            ResponseContent::Text(_) => Some("text/plain; charset=utf-8"),
+            ResponseContent::Bytes { content_type, .. } => Some(content_type),
+            ResponseContent::File(path) => Some(mime::from_path(path)),
+            ResponseContent::Redirect { .. } | ResponseContent::Empty => None,
+            ResponseContent::Stream { content_type, .. } => Some(content_type),
+        }
+    }
+
+    /// The status a response with this content starts out with.
+    fn status(&self) -> StatusCode {
+        match self {
+            ResponseContent::Redirect {
+                permanent: true, ..
+            } => StatusCode::MOVED_PERMANENTLY,
+            ResponseContent::Redirect {
+                permanent: false, ..
+            } => StatusCode::FOUND,
+            _ => StatusCode::OK,
+        }
+    }
+
+    /// The length of the body, if it is known before it is sent.
+    fn length(&self) -> Option<usize> {
         match self {
-            ResponseContent::Json(json) => json.to_string(),
-            ResponseContent::Html(html) => html.to_string(),
+            ResponseContent::Json(json) => Some(json.len()),
+            ResponseContent::Html(html) => Some(html.len()),
+            ResponseContent::Text(text) => Some(text.len()),
+            ResponseContent::Bytes { data, .. } => Some(data.len()),
+            ResponseContent::Redirect { .. } | ResponseContent::Empty => Some(0),
+            ResponseContent::File(_) | ResponseContent::Stream { .. } => None,
+        }
+    }
+
+    fn into_body(self) -> Body {
+        match self {
+            ResponseContent::Json(json) => Body::Full(json.into_bytes()),
+            ResponseContent::Html(html) => Body::Full(html.into_bytes()),
+            ResponseContent::Text(text) => Body::Full(text.into_bytes()),
+            ResponseContent::Bytes { data, .. } => Body::Full(data),
+            ResponseContent::Redirect { .. } | ResponseContent::Empty => Body::Full(Vec::new()),
+            ResponseContent::Stream { chunks, .. } => Body::Chunks(chunks),
+            // Files are opened into streams by `Response::open`, so one that
+            // reaches here is read as it is sent.
+            ResponseContent::File(path) => match File::open(path) {
+                Ok(file) => Body::Chunks(Box::new(ReadChunks(Some(file)))),
+                Err(err) => Body::Chunks(Box::new(iter::once(Err(OxidarError::Abortion(
+                    Error::Io(err),
+                ))))),
+            },
+        }
+    }
+}
+
+/// A response body, either already in memory or still to be produced.
+pub(crate) enum Body {
+    Full(Vec<u8>),
+    Chunks(Chunks),
+}
+
+/// Reads chunks from a reader until it runs out.
+struct ReadChunks<R>(Option<R>);
+
+impl<R: Read> Iterator for ReadChunks<R> {
+    type Item = Result<Vec<u8>, OxidarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = self.0.as_mut()?;
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => {
+                    self.0 = None;
+                    return None;
+                }
+                Ok(n) => {
+                    chunk.truncate(n);
+                    return Some(Ok(chunk));
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    self.0 = None;
+                    return Some(Err(OxidarError::Abortion(Error::Io(err))));
+                }
+            }
+        }
+    }
+}
+
+/// Reads exactly `length` bytes, for a body sent with a `Content-Length`. A
+/// file that shrinks after its length was taken ends in an error, so the
+/// connection is aborted rather than left waiting for bytes that never come.
+pub(crate) struct ExactReader<R>(Take<R>);
+
+impl<R: Read> ExactReader<R> {
+    pub(crate) fn new(reader: R, length: u64) -> ExactReader<R> {
+        ExactReader(reader.take(length))
+    }
+}
+
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buf)?;
+        if read == 0 && !buf.is_empty() && self.0.limit() > 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "The body is shorter than its length.",
+            ));
         }
+        Ok(read)
     }
 }
 
+/// Frames a chunk for chunked transfer encoding. An empty chunk would end the
+/// body, so it is framed as nothing at all.
+fn encode_chunk(chunk: Vec<u8>) -> Vec<u8> {
+    if chunk.is_empty() {
+        return chunk;
+    }
+
+    let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+    framed.extend_from_slice(&chunk);
+    framed.extend_from_slice(b"\r\n");
+    framed
+}
+
+/// Ends a body sent with chunked transfer encoding.
+const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
 pub struct Response {
     pub version: Version,
-    pub status: &'static str,
+    pub status: StatusCode,
+    pub headers: Headers,
     pub content: ResponseContent,
 }
 
 impl Response {
-    pub(crate) fn build_response(&self) -> String {
-        let response = format!(
-            "{} {}\r\n\r\n{}",
-            self.version,
-            self.status,
-            self.content.get_body()
-        );
-        return response;
+    /// Creates a response with no extra headers. Its status is `200 OK`,
+    /// except for redirects.
+    pub fn new(content: ResponseContent) -> Response {
+        Response {
+            version: Version::Http1_1,
+            status: content.status(),
+            headers: Headers::new(),
+            content,
+        }
+    }
+
+    pub fn status(mut self, status: StatusCode) -> Response {
+        self.status = status;
+        self
+    }
+
+    /// Sets a header, replacing any value it already has.
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds a value to a header, keeping any values it already has.
+    pub fn append_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Sets a cookie with a `Set-Cookie` header. Several cookies can be set on
+    /// one response.
+    pub fn cookie(self, cookie: Cookie) -> Response {
+        self.append_header("Set-Cookie", &cookie.to_header())
+    }
+
+    /// Tells the browser to drop a cookie, by setting it empty and already
+    /// expired. The path and domain must match the ones it was set with.
+    pub fn remove_cookie(self, cookie: Cookie) -> Response {
+        self.cookie(cookie.removal())
+    }
+
+    /// Opens the file of a `File` response, turning it into a stream of known
+    /// length, so a file that can not be read gets an error response before
+    /// anything is sent.
+    pub(crate) fn open(self) -> Result<Response, OxidarError> {
+        let path = match self.content {
+            ResponseContent::File(ref path) => path.clone(),
+            _ => return Ok(self),
+        };
+
+        let not_found = || OxidarError::http_404(Some("The file could not be found.".to_string()));
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Err(not_found()),
+            Err(err) => return Err(OxidarError::Normal(Error::Io(err))),
+        };
+
+        let metadata = OxidarError::nio(file.metadata())?;
+        if !metadata.is_file() {
+            return Err(not_found());
+        }
+
+        let content_type = mime::from_path(&path);
+        let length = metadata.len();
+        let content = ResponseContent::reader(content_type, ExactReader::new(file, length));
+
+        Ok(Response {
+            content,
+            ..self.header("Content-Length", &length.to_string())
+        })
+    }
+
+    /// Builds the raw response, for responses such as error pages that are
+    /// built in memory. A streamed body is left out.
+    pub(crate) fn build_response(self) -> Vec<u8> {
+        self.into_raw(false, true).0
+    }
+
+    /// Builds the raw response for an HTTP/1 connection. `Content-Type` is
+    /// derived from the content unless it has been set, and `Content-Length`
+    /// is the byte length of the body where it is known up front.
+    ///
+    /// A streamed body is returned separately, to be written as its chunks are
+    /// produced. A body of unknown length is framed with chunked transfer
+    /// encoding if `chunked` is set, or else ended by closing the connection.
+    /// With `head_only` the body is left out, as used to answer `HEAD`
+    /// requests. It is also left out for statuses that never have a body.
+    pub(crate) fn into_raw(self, head_only: bool, chunked: bool) -> (Vec<u8>, Option<Chunks>) {
+        let head_only = head_only || !self.has_body();
+        let length = self.body_length();
+        let chunked = chunked && length.is_none();
+
+        let mut head = format!("{} {}\r\n", self.version, self.status);
+        for (name, value) in self.fields(length, chunked).iter() {
+            write_header(&mut head, name, value);
+        }
+        head.push_str("\r\n");
+
+        let mut response = head.into_bytes();
+        match (head_only, self.content.into_body()) {
+            (true, _) => (response, None),
+            (false, Body::Full(body)) => {
+                response.extend_from_slice(&body);
+                (response, None)
+            }
+            (false, Body::Chunks(chunks)) if chunked => {
+                let chunks = chunks
+                    .map(|chunk| chunk.map(encode_chunk))
+                    .chain(iter::once(Ok(LAST_CHUNK.to_vec())));
+                (response, Some(Box::new(chunks)))
+            }
+            (false, Body::Chunks(chunks)) => (response, Some(chunks)),
+        }
+    }
+
+    /// Splits the response into its status, the header fields it is sent
+    /// with, and its body, for protocols that do not send a raw head.
+    #[cfg(feature = "http2")]
+    pub(crate) fn into_parts(self) -> (StatusCode, Headers, Body) {
+        let fields = self.fields(self.body_length(), false);
+        let body = match self.has_body() {
+            true => self.content.into_body(),
+            false => Body::Full(Vec::new()),
+        };
+        (self.status, fields, body)
+    }
+
+    /// Whether the status allows a body. Informational, `204` and `304`
+    /// responses never have one, whatever their content.
+    pub(crate) fn has_body(&self) -> bool {
+        !(self.status.is_informational()
+            || self.status == StatusCode::NO_CONTENT
+            || self.status == StatusCode::NOT_MODIFIED)
+    }
+
+    /// The length of the body if it is known before it is sent. A stream's
+    /// length is known if its `Content-Length` header has been set.
+    pub(crate) fn body_length(&self) -> Option<usize> {
+        match self.content {
+            ResponseContent::File(_) | ResponseContent::Stream { .. } => self
+                .headers
+                .get("Content-Length")
+                .and_then(|length| length.trim().parse().ok()),
+            ref content => content.length(),
+        }
+    }
+
+    /// The header fields the response is sent with. `length` is the length
+    /// of the body if it is known up front.
+    fn fields(&self, length: Option<usize>, chunked: bool) -> Headers {
+        let mut fields = Headers::new();
+
+        if !self.headers.contains("Content-Type") {
+            if let Some(content_type) = self.content.content_type() {
+                fields.append("Content-Type", content_type);
+            }
+        }
+
+        if let ResponseContent::Redirect { ref location, .. } = self.content {
+            if !self.headers.contains("Location") {
+                fields.append("Location", location);
+            }
+        }
+
+        for (name, value) in self.headers.iter() {
+            if !name.eq_ignore_ascii_case("Content-Length")
+                && !name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                fields.append(name, value);
+            }
+        }
+
+        // Responses that never have a body do not say how long it is.
+        if !self.has_body() {
+            return fields;
+        }
+
+        match length {
+            Some(length) => fields.append("Content-Length", &length.to_string()),
+            None if chunked => fields.append("Transfer-Encoding", "chunked"),
+            None => {}
+        }
+
+        fields
+    }
+}
+
+/// Anything a view may return. Errors are rendered with
+/// `OxidarError::to_response`.
+pub trait IntoResponse {
+    fn into_response(self) -> Result<Response, OxidarError>;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Result<Response, OxidarError> {
+        Ok(self)
+    }
+}
+
+impl IntoResponse for ResponseContent {
+    fn into_response(self) -> Result<Response, OxidarError> {
+        Ok(Response::new(self))
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Result<Response, OxidarError> {
+        Ok(Response::new(ResponseContent::Html(self)))
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Result<Response, OxidarError> {
+        Ok(Response::new(ResponseContent::Html(self.to_string())))
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Result<Response, OxidarError> {
+        Ok(self.1.into_response()?.status(self.0))
+    }
+}
+
+impl<T, E> IntoResponse for Result<T, E>
+where
+    T: IntoResponse,
+    E: Into<OxidarError>,
+{
+    fn into_response(self) -> Result<Response, OxidarError> {
+        match self {
+            Ok(ok) => ok.into_response(),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Writes a header line, dropping any line breaks so a value can not start a
+/// new header.
+pub(crate) fn write_header(response: &mut String, name: &str, value: &str) {
+    let clean = |s: &str| s.replace(['\r', '\n'], "");
+    response.push_str(&format!("{}: {}\r\n", clean(name), clean(value)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(chunks: &[&str]) -> ResponseContent {
+        let chunks: Vec<_> = chunks.iter().map(|c| Ok(c.as_bytes().to_vec())).collect();
+        ResponseContent::stream("text/plain", chunks.into_iter())
+    }
+
+    /// The raw response, with any streamed body written after the head.
+    fn raw(response: Response, head_only: bool, chunked: bool) -> String {
+        let (mut raw, chunks) = response.into_raw(head_only, chunked);
+        for chunk in chunks.into_iter().flatten() {
+            raw.extend(chunk.unwrap());
+        }
+        String::from_utf8(raw).unwrap()
+    }
+
+    #[test]
+    fn frames_bodies() {
+        let response = Response::new(ResponseContent::Text("hello".to_string()));
+        assert_eq!(
+            raw(response, false, true),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 5\r\n\r\nhello"
+        );
+
+        let response = Response::new(stream(&["ab", "", "cde"]));
+        assert_eq!(
+            raw(response, false, true),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n\
+             2\r\nab\r\n3\r\ncde\r\n0\r\n\r\n"
+        );
+
+        let response = Response::new(stream(&["ab", "cde"])).header("Content-Length", "5");
+        assert_eq!(
+            raw(response, false, true),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nabcde"
+        );
+
+        // Without chunked encoding the body is ended by closing the connection.
+        let response = Response::new(stream(&["ab"]));
+        assert_eq!(
+            raw(response, false, false),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nab"
+        );
+    }
+
+    #[test]
+    fn builds_bytes_redirect_and_file_responses() {
+        let response = Response::new(ResponseContent::Bytes {
+            content_type: "image/png".to_string(),
+            data: vec![1, 2, 3],
+        });
+        assert_eq!(
+            raw(response, false, true).as_bytes(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 3\r\n\r\n\x01\x02\x03"
+        );
+
+        let response = Response::new(ResponseContent::Redirect {
+            location: "/next".to_string(),
+            permanent: true,
+        });
+        assert_eq!(
+            raw(response, false, true),
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: /next\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        let path = std::env::temp_dir().join(format!("oxidar-file-{}.txt", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+        let response = Response::new(ResponseContent::File(path.clone()))
+            .open()
+            .unwrap();
+        assert_eq!(
+            raw(response, false, true),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 5\r\n\r\nhello"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        let missing = Response::new(ResponseContent::File(path)).open();
+        assert!(matches!(
+            missing,
+            Err(OxidarError::Normal(Error::Http(StatusCode::NOT_FOUND, _)))
+        ));
+    }
+
+    #[test]
+    fn fails_bodies_shorter_than_their_length() {
+        let mut reader = ExactReader::new(&b"hello"[..], 3);
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hel");
+
+        let response = Response::new(ResponseContent::reader(
+            "text/plain",
+            ExactReader::new(&b"he"[..], 5),
+        ));
+        let (_, chunks) = response.into_raw(false, false);
+        let chunks: Vec<_> = chunks.unwrap().collect();
+        assert_eq!(chunks[0].as_ref().unwrap(), b"he");
+        assert!(matches!(
+            chunks[1],
+            Err(OxidarError::Abortion(Error::Io(ref err))) if err.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn leaves_out_bodies_of_head_responses() {
+        let response = Response::new(ResponseContent::Text("hello".to_string()));
+        assert!(raw(response, true, true).ends_with("Content-Length: 5\r\n\r\n"));
+
+        let response = Response::new(stream(&["ab"]));
+        assert!(raw(response, true, true).ends_with("Transfer-Encoding: chunked\r\n\r\n"));
+    }
+
+    #[test]
+    fn drops_bodies_statuses_do_not_allow() {
+        for status in [
+            StatusCode::SWITCHING_PROTOCOLS,
+            StatusCode::NO_CONTENT,
+            StatusCode::NOT_MODIFIED,
+        ] {
+            let response = Response::new(stream(&["ab"]))
+                .status(status)
+                .header("Transfer-Encoding", "chunked");
+            let (head, chunks) = response.into_raw(false, true);
+            let head = String::from_utf8(head).unwrap();
+
+            assert!(chunks.is_none(), "{status}");
+            assert!(!head.contains("Transfer-Encoding"), "{status}");
+            assert!(
+                head.ends_with("Content-Type: text/plain\r\n\r\n"),
+                "{status}"
+            );
+
+            let response = Response::new(ResponseContent::Text("hello".to_string())).status(status);
+            let raw = raw(response, false, true);
+            assert!(!raw.contains("Content-Length"), "{status}");
+            assert!(raw.ends_with("\r\n\r\n"), "{status}");
+        }
+    }
+
+    #[cfg(feature = "http2")]
+    #[test]
+    fn drops_bodies_statuses_do_not_allow_over_http2() {
+        let response = Response::new(stream(&["ab"])).status(StatusCode::NOT_MODIFIED);
+        let (_, fields, body) = response.into_parts();
+
+        assert!(!fields.contains("Content-Length"));
+        assert!(matches!(body, Body::Full(body) if body.is_empty()));
+    }
+
+    #[test]
+    fn derives_content_headers() {
+        let head = |response: Response| {
+            let raw = raw(response, true, true);
+            raw[..raw.len() - 4]
+                .split("\r\n")
+                .skip(1)
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let response = Response::new(ResponseContent::Json("{}".to_string()));
+        assert_eq!(
+            head(response),
+            ["Content-Type: application/json", "Content-Length: 2"]
+        );
+
+        let response = Response::new(ResponseContent::Html("<p>".to_string()))
+            .header("Content-Type", "text/html; charset=latin1")
+            .header("Content-Length", "99")
+            .header("X-Id", "1");
+        assert_eq!(
+            head(response),
+            [
+                "Content-Type: text/html; charset=latin1",
+                "X-Id: 1",
+                "Content-Length: 3"
+            ]
+        );
+
+        let response = Response::new(ResponseContent::Empty);
+        assert_eq!(head(response), ["Content-Length: 0"]);
+    }
+
+    #[test]
+    fn keeps_header_values_on_one_line() {
+        let response = Response::new(ResponseContent::Empty).header("X-Name", "a\r\nSet-Cookie: b");
+        assert!(raw(response, false, true).contains("X-Name: aSet-Cookie: b\r\n"));
     }
 }
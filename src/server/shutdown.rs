@@ -0,0 +1,119 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// How many SIGINT and SIGTERM signals the process has received.
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+/// The value of `ShutdownHandle::signals_seen` while signals are ignored.
+const IGNORING_SIGNALS: usize = usize::MAX;
+
+/// Stops a running `Oxidar`. The server stops accepting connections, lets
+/// requests in flight finish, and returns from `Oxidar::run`.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    /// The signal count when the server started listening for signals, so
+    /// only signals received after that shut it down.
+    signals_seen: Arc<AtomicUsize>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> ShutdownHandle {
+        ShutdownHandle::new()
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            requested: Arc::new(AtomicBool::new(false)),
+            signals_seen: Arc::new(AtomicUsize::new(IGNORING_SIGNALS)),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    /// True once `shutdown` has been called or, if signal handling is on, the
+    /// process has received SIGINT or SIGTERM since the server started.
+    pub fn is_shutting_down(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+            || SIGNALS.load(Ordering::SeqCst) > self.signals_seen.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::SIGNALS;
+    use std::{os::raw::c_int, sync::atomic::Ordering};
+
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_signal(signum: c_int) {
+        SIGNALS.fetch_add(1, Ordering::SeqCst);
+
+        // A second signal ends the process right away.
+        unsafe {
+            signal(signum, SIG_DFL);
+        }
+    }
+
+    pub(super) fn install() {
+        unsafe {
+            signal(SIGINT, on_signal as extern "C" fn(c_int) as usize);
+            signal(SIGTERM, on_signal as extern "C" fn(c_int) as usize);
+        }
+    }
+}
+
+/// Makes SIGINT and SIGTERM start a graceful shutdown through `handle`.
+/// Signals received before this call, or caught for another server, are not
+/// seen by it. Does nothing on platforms other than unix.
+pub(crate) fn install_signal_handlers(handle: &ShutdownHandle) {
+    #[cfg(unix)]
+    {
+        handle
+            .signals_seen
+            .store(SIGNALS.load(Ordering::SeqCst), Ordering::SeqCst);
+        unix::install();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_the_shutdown() {
+        let handle = ShutdownHandle::new();
+        let clone = handle.clone();
+        assert!(!handle.is_shutting_down());
+
+        clone.shutdown();
+        assert!(handle.is_shutting_down());
+    }
+
+    #[test]
+    fn only_counts_signals_once_handlers_are_installed() {
+        let handle = ShutdownHandle::new();
+        SIGNALS.fetch_add(1, Ordering::SeqCst);
+        assert!(!handle.is_shutting_down());
+
+        handle
+            .signals_seen
+            .store(SIGNALS.load(Ordering::SeqCst), Ordering::SeqCst);
+        assert!(!handle.is_shutting_down());
+
+        SIGNALS.fetch_add(1, Ordering::SeqCst);
+        assert!(handle.is_shutting_down());
+    }
+}
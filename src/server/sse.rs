@@ -0,0 +1,169 @@
+use std::{
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender},
+    time::Duration,
+};
+
+use super::response::{IntoResponse, Response, ResponseContent};
+use crate::errors::OxidarError;
+
+/// A server-sent event.
+///
+/// ```ignore
+/// Event::new("{\"cpu\": 12}").event("stats").id("42")
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    /// Creates an event carrying `data`, which may span several lines.
+    pub fn new(data: &str) -> Event {
+        Event {
+            id: None,
+            event: None,
+            data: data.to_string(),
+        }
+    }
+
+    /// Sets the id the browser sends back in `Last-Event-ID` when it
+    /// reconnects, so a view can resume the stream where it left off.
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Sets the event type, which picks the listener it is dispatched to in
+    /// the browser. Events without one are `message` events.
+    pub fn event(mut self, event: &str) -> Event {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    fn encode(&self) -> String {
+        // Line breaks would end a field early, so they are dropped from
+        // single line fields and split into separate lines of data.
+        let clean = |s: &str| s.replace(['\r', '\n'], "");
+        let mut encoded = String::new();
+
+        if let Some(ref event) = self.event {
+            encoded.push_str(&format!("event: {}\n", clean(event)));
+        }
+
+        if let Some(ref id) = self.id {
+            encoded.push_str(&format!("id: {}\n", clean(id).replace('\0', "")));
+        }
+
+        for line in self.data.split('\n') {
+            encoded.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+
+        encoded.push('\n');
+        encoded
+    }
+}
+
+/// Sends events to an `Sse` response. Sending fails once the client has
+/// disconnected, which is how the thread producing the events knows to stop.
+#[derive(Debug, Clone)]
+pub struct EventSender(Sender<Event>);
+
+impl EventSender {
+    pub fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        self.0.send(event)
+    }
+}
+
+/// A `text/event-stream` response whose events are sent as they are produced
+/// by an `EventSender`. The stream ends once every sender has been dropped.
+///
+/// ```ignore
+/// let (sender, sse) = Sse::channel();
+/// thread::spawn(move || {
+///     for line in tail("app.log") {
+///         if sender.send(Event::new(&line)).is_err() {
+///             break;
+///         }
+///     }
+/// });
+///
+/// sse.heartbeat(Duration::from_secs(15))
+/// ```
+pub struct Sse {
+    events: Receiver<Event>,
+    retry: Option<Duration>,
+    heartbeat: Option<Duration>,
+}
+
+impl Sse {
+    pub fn channel() -> (EventSender, Sse) {
+        let (sender, events) = mpsc::channel();
+        let sse = Sse {
+            events,
+            retry: None,
+            heartbeat: None,
+        };
+
+        (EventSender(sender), sse)
+    }
+
+    /// Tells the browser how long to wait before reconnecting if the stream
+    /// is cut.
+    pub fn retry(mut self, retry: Duration) -> Sse {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Sends a comment whenever no event has been sent for `interval`, so
+    /// proxies do not close the connection as idle and a client that has gone
+    /// away is noticed.
+    pub fn heartbeat(mut self, interval: Duration) -> Sse {
+        self.heartbeat = Some(interval);
+        self
+    }
+}
+
+impl IntoResponse for Sse {
+    fn into_response(self) -> Result<Response, OxidarError> {
+        let chunks = SseChunks {
+            retry: self.retry,
+            heartbeat: self.heartbeat,
+            events: self.events,
+        };
+
+        let response = Response::new(ResponseContent::stream("text/event-stream", chunks))
+            .header("Cache-Control", "no-cache")
+            .header("X-Accel-Buffering", "no");
+
+        Ok(response)
+    }
+}
+
+struct SseChunks {
+    retry: Option<Duration>,
+    heartbeat: Option<Duration>,
+    events: Receiver<Event>,
+}
+
+impl Iterator for SseChunks {
+    type Item = Result<Vec<u8>, OxidarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(retry) = self.retry.take() {
+            return Some(Ok(format!("retry: {}\n\n", retry.as_millis()).into_bytes()));
+        }
+
+        let event = match self.heartbeat {
+            Some(interval) => match self.events.recv_timeout(interval) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => return Some(Ok(b": heartbeat\n\n".to_vec())),
+                Err(RecvTimeoutError::Disconnected) => return None,
+            },
+            None => self.events.recv().ok()?,
+        };
+
+        Some(Ok(event.encode().into_bytes()))
+    }
+}
@@ -0,0 +1,37 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::HashMap,
+};
+
+use crate::errors::{Error, OxidarError};
+
+/// Shared values looked up by their type, used to give views access to
+/// services such as database pools without globals.
+#[derive(Default)]
+pub struct State {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            values: HashMap::new(),
+        }
+    }
+
+    /// Stores a value, replacing any value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    /// Gets the value of type `T`. A missing value results in a 500 error.
+    pub fn get<T: Any + Send + Sync>(&self) -> Result<&T, OxidarError> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+            .ok_or(OxidarError::Normal(Error::Untyped(format!(
+                "No state of type \"{}\" has been registered.",
+                type_name::<T>()
+            ))))
+    }
+}
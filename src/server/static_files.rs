@@ -0,0 +1,588 @@
+use std::{
+    fs::{self, File, Metadata},
+    io::{Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    app::{App, ViewReg},
+    date::{format_http_date, parse_http_date},
+    http::StatusCode,
+    mime,
+    request::Request,
+    response::{ExactReader, Response, ResponseContent},
+};
+use crate::errors::OxidarError;
+
+/// Precompressed variants looked for next to a file, most preferred first, as
+/// the content coding and the extension of the file holding it.
+const ENCODINGS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Serves the files in a directory, registered under a prefix like any other
+/// app.
+///
+/// ```ignore
+/// AppReg::p("/static", StaticFiles::new("static").cache_control("max-age=3600").app())
+/// ```
+///
+/// Files are sent with their type guessed from their extension, and with an
+/// `ETag` and `Last-Modified` so browsers can revalidate them with a `304`.
+/// Single byte ranges are answered with a `206`. If the client accepts it, a
+/// precompressed `.br` or `.gz` file next to the requested one is sent in its
+/// place. Paths that would leave the directory get a 404.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    precompressed: bool,
+    cache_control: Option<String>,
+}
+
+impl StaticFiles {
+    pub fn new<P: AsRef<Path>>(root: P) -> StaticFiles {
+        StaticFiles {
+            root: root.as_ref().to_path_buf(),
+            precompressed: true,
+            cache_control: None,
+        }
+    }
+
+    /// Sets whether precompressed `.br` and `.gz` variants are looked for.
+    /// They are by default.
+    pub fn precompressed(mut self, precompressed: bool) -> StaticFiles {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// Sets the `Cache-Control` header sent with every file.
+    pub fn cache_control(mut self, value: &str) -> StaticFiles {
+        self.cache_control = Some(value.to_string());
+        self
+    }
+
+    /// Builds the app serving the files.
+    pub fn app(self) -> App {
+        App::new(vec![ViewReg::get("/<path:file>", move |_, request| {
+            self.serve(request)
+        })])
+    }
+
+    fn serve(&self, request: &Request) -> Result<Response, OxidarError> {
+        let not_found =
+            || OxidarError::http_404(Some(format!("No file found for \"{}\".", request.path)));
+
+        let file: String = request.param("file")?;
+        let path = self.resolve(&file).ok_or_else(not_found)?;
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Err(not_found()),
+        };
+
+        let content_type = mime::from_path(&path);
+        let (path, metadata, encoding) = match self.precompressed {
+            true => self.variant(request, path, metadata),
+            false => (path, metadata, None),
+        };
+
+        let modified = metadata.modified().ok();
+        let etag = Self::etag(&metadata, encoding);
+
+        let mut headers = vec![("ETag", etag.clone())];
+        if let Some(modified) = modified {
+            headers.push(("Last-Modified", format_http_date(modified)));
+        }
+        if let Some(ref cache_control) = self.cache_control {
+            headers.push(("Cache-Control", cache_control.clone()));
+        }
+        if self.precompressed {
+            headers.push(("Vary", "Accept-Encoding".to_string()));
+        }
+        match encoding {
+            Some(encoding) => headers.push(("Content-Encoding", encoding.to_string())),
+            None => headers.push(("Accept-Ranges", "bytes".to_string())),
+        }
+
+        let with_headers = |mut response: Response| {
+            for (name, value) in &headers {
+                response = response.header(name, value);
+            }
+            response
+        };
+
+        if Self::not_modified(request, &etag, modified) {
+            let response = Response::new(ResponseContent::Empty).status(StatusCode::NOT_MODIFIED);
+            return Ok(with_headers(response));
+        }
+
+        let mut file = OxidarError::nio(File::open(&path))?;
+        let length = metadata.len();
+
+        // Ranges of an encoded file would be ranges of the compressed bytes,
+        // which clients rarely want, so they are only served unencoded.
+        let range = match encoding {
+            Some(_) => None,
+            None => Self::range(request, &etag, modified, length),
+        };
+
+        let (status, start, end) = match range {
+            None => (StatusCode::OK, 0, length),
+            Some(Ok((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+            Some(Err(())) => {
+                let err = OxidarError::http(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    Some("The requested range is not satisfiable.".to_string()),
+                );
+
+                return match err.to_response() {
+                    Some(response) => Ok(with_headers(response)
+                        .header("Content-Range", &format!("bytes */{length}"))),
+                    None => Err(err),
+                };
+            }
+        };
+
+        OxidarError::nio(file.seek(SeekFrom::Start(start)))?;
+        let content = ResponseContent::reader(content_type, ExactReader::new(file, end - start));
+        let mut response = with_headers(Response::new(content).status(status))
+            .header("Content-Length", &(end - start).to_string());
+
+        if status == StatusCode::PARTIAL_CONTENT {
+            let content_range = format!("bytes {start}-{}/{length}", end - 1);
+            response = response.header("Content-Range", &content_range);
+        }
+
+        Ok(response)
+    }
+
+    /// Joins the requested path onto the root, refusing any path that could
+    /// lead outside of it, including through symlinks.
+    fn resolve(&self, file: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+
+        for part in file.split('/') {
+            let mut components = Path::new(part).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) if !part.contains('\0') => path.push(part),
+                _ => return None,
+            }
+        }
+
+        let root = self.root.canonicalize().ok()?;
+        let path = path.canonicalize().ok()?;
+        path.starts_with(&root).then_some(path)
+    }
+
+    /// Picks a precompressed variant of the file that the client accepts, if
+    /// one exists. Variants are held to the root like the file itself.
+    fn variant(
+        &self,
+        request: &Request,
+        path: PathBuf,
+        metadata: Metadata,
+    ) -> (PathBuf, Metadata, Option<&'static str>) {
+        let accepted = request.header("Accept-Encoding").unwrap_or_default();
+        let root = match self.root.canonicalize() {
+            Ok(root) => root,
+            Err(_) => return (path, metadata, None),
+        };
+
+        for (encoding, extension) in ENCODINGS {
+            if !Self::accepts(accepted, encoding) {
+                continue;
+            }
+
+            let mut variant = path.clone().into_os_string();
+            variant.push(format!(".{extension}"));
+            let variant = match PathBuf::from(variant).canonicalize() {
+                Ok(variant) if variant.starts_with(&root) => variant,
+                _ => continue,
+            };
+
+            if let Ok(metadata) = fs::metadata(&variant) {
+                if metadata.is_file() {
+                    return (variant, metadata, Some(encoding));
+                }
+            }
+        }
+
+        (path, metadata, None)
+    }
+
+    /// Whether an `Accept-Encoding` header accepts `encoding`, either by name
+    /// or through `*`, with a nonzero quality.
+    fn accepts(accepted: &str, encoding: &str) -> bool {
+        accepted.split(',').any(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            (name.eq_ignore_ascii_case(encoding) || name == "*") && quality > 0.0
+        })
+    }
+
+    /// A strong validator built from the size and modification time of the
+    /// file, and the coding it is sent with.
+    fn etag(metadata: &Metadata, encoding: Option<&str>) -> String {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_nanos());
+
+        match encoding {
+            Some(encoding) => format!("\"{modified:x}-{:x}-{encoding}\"", metadata.len()),
+            None => format!("\"{modified:x}-{:x}\"", metadata.len()),
+        }
+    }
+
+    /// Checks `If-None-Match`, or `If-Modified-Since` when it is absent, to
+    /// see if the client's copy is still current.
+    fn not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+        if let Some(none_match) = request.header("If-None-Match") {
+            let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+            return none_match
+                .split(',')
+                .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag));
+        }
+
+        match (
+            request
+                .header("If-Modified-Since")
+                .and_then(parse_http_date),
+            modified,
+        ) {
+            (Some(since), Some(modified)) => Self::whole_seconds(modified) <= since,
+            _ => false,
+        }
+    }
+
+    /// Works out the byte range asked for with `Range`, as a start and an
+    /// exclusive end. Returns `None` if the whole file should be sent, which
+    /// includes headers that can not be parsed, requests for several ranges,
+    /// and ranges of a file that has changed since `If-Range`. Returns an
+    /// error if the range lies outside the file.
+    fn range(
+        request: &Request,
+        etag: &str,
+        modified: Option<SystemTime>,
+        length: u64,
+    ) -> Option<Result<(u64, u64), ()>> {
+        let range = request.header("Range")?.trim().strip_prefix("bytes=")?;
+
+        if let Some(if_range) = request.header("If-Range") {
+            let current = match parse_http_date(if_range) {
+                Some(date) => modified.is_some_and(|m| Self::whole_seconds(m) == date),
+                None => if_range.trim() == etag,
+            };
+
+            if !current {
+                return None;
+            }
+        }
+
+        if range.contains(',') {
+            return None;
+        }
+
+        let (start, end) = range.trim().split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+
+        if start.is_empty() {
+            let suffix = Self::digits(end)?;
+            return match suffix > 0 && length > 0 {
+                true => Some(Ok((length.saturating_sub(suffix), length))),
+                false => Some(Err(())),
+            };
+        }
+
+        let start = Self::digits(start)?;
+        let end = match end.is_empty() {
+            true => length,
+            false => {
+                let end = Self::digits(end)?;
+                if end < start {
+                    return None;
+                }
+                end.saturating_add(1).min(length)
+            }
+        };
+
+        match start < length {
+            true => Some(Ok((start, end))),
+            false => Some(Err(())),
+        }
+    }
+
+    /// Parses a position in a range, which may only be digits. `parse` would
+    /// also take a leading `+`.
+    fn digits(value: &str) -> Option<u64> {
+        match value.bytes().all(|b| b.is_ascii_digit()) {
+            true => value.parse().ok(),
+            false => None,
+        }
+    }
+
+    /// HTTP dates only have whole seconds, so times are rounded down before
+    /// they are compared with one.
+    fn whole_seconds(time: SystemTime) -> SystemTime {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{http::Method, request::test_request};
+
+    /// A directory of files to serve, removed when dropped.
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(label: &str) -> Root {
+            let dir =
+                std::env::temp_dir().join(format!("oxidar-static-{}-{label}", std::process::id()));
+            let root = dir.join("root");
+            fs::create_dir_all(root.join("dir")).unwrap();
+            fs::write(root.join("digits.txt"), "0123456789").unwrap();
+            fs::write(root.join("app.js"), "plain").unwrap();
+            fs::write(root.join("app.js.gz"), "gz").unwrap();
+            fs::write(dir.join("secret.txt"), "secret").unwrap();
+            Root(dir)
+        }
+
+        fn files(&self) -> StaticFiles {
+            StaticFiles::new(self.0.join("root"))
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn request(file: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = test_request(Method::GET, &format!("/{file}"), headers, b"");
+        request.params.insert("file".to_string(), file.to_string());
+        request
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        match response.into_raw(false, false).1 {
+            Some(chunks) => chunks.flat_map(|chunk| chunk.unwrap()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn range(header: &str, if_range: Option<&str>) -> Option<Result<(u64, u64), ()>> {
+        let mut headers = vec![("Range", header)];
+        if let Some(if_range) = if_range {
+            headers.push(("If-Range", if_range));
+        }
+
+        let modified = UNIX_EPOCH + Duration::from_secs(784111777);
+        StaticFiles::range(&request("f", &headers), "\"tag\"", Some(modified), 10)
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(range("bytes=0-4", None), Some(Ok((0, 5))));
+        assert_eq!(range("bytes=5-", None), Some(Ok((5, 10))));
+        assert_eq!(range("bytes=-3", None), Some(Ok((7, 10))));
+        assert_eq!(range("bytes=-20", None), Some(Ok((0, 10))));
+        assert_eq!(range("bytes=8-100", None), Some(Ok((8, 10))));
+        assert_eq!(range("bytes=10-", None), Some(Err(())));
+        assert_eq!(range("bytes=-0", None), Some(Err(())));
+
+        for ignored in [
+            "bytes=5-4",
+            "bytes=0-1,3-4",
+            "items=0-1",
+            "bytes=+0-4",
+            "bytes=0-+4",
+            "bytes=-+3",
+            "bytes=a-b",
+            "bytes=",
+        ] {
+            assert_eq!(range(ignored, None), None, "{ignored}");
+        }
+    }
+
+    #[test]
+    fn only_serves_ranges_of_the_same_file() {
+        assert_eq!(range("bytes=0-4", Some("\"tag\"")), Some(Ok((0, 5))));
+        assert_eq!(range("bytes=0-4", Some("\"other\"")), None);
+        assert_eq!(range("bytes=0-4", Some("W/\"tag\"")), None);
+
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(range("bytes=0-4", Some(date)), Some(Ok((0, 5))));
+        assert_eq!(
+            range("bytes=0-4", Some("Sun, 06 Nov 1994 08:49:36 GMT")),
+            None
+        );
+    }
+
+    #[test]
+    fn checks_conditional_headers() {
+        let modified =
+            Some(UNIX_EPOCH + Duration::from_secs(784111777) + Duration::from_millis(500));
+        let not_modified = |headers: &[(&str, &str)]| {
+            StaticFiles::not_modified(&request("f", headers), "\"tag\"", modified)
+        };
+
+        assert!(not_modified(&[("If-None-Match", "\"tag\"")]));
+        assert!(not_modified(&[("If-None-Match", "\"a\", W/\"tag\"")]));
+        assert!(not_modified(&[("If-None-Match", "*")]));
+        assert!(!not_modified(&[("If-None-Match", "\"other\"")]));
+
+        assert!(not_modified(&[(
+            "If-Modified-Since",
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        )]));
+        assert!(not_modified(&[(
+            "If-Modified-Since",
+            "Sun, 06 Nov 1994 09:00:00 GMT"
+        )]));
+        assert!(!not_modified(&[(
+            "If-Modified-Since",
+            "Sun, 06 Nov 1994 08:49:36 GMT"
+        )]));
+        assert!(!not_modified(&[("If-Modified-Since", "yesterday")]));
+
+        // If-None-Match wins over If-Modified-Since.
+        assert!(!not_modified(&[
+            ("If-None-Match", "\"other\""),
+            ("If-Modified-Since", "Sun, 06 Nov 1994 09:00:00 GMT"),
+        ]));
+    }
+
+    #[test]
+    fn reads_accept_encoding() {
+        assert!(StaticFiles::accepts("gzip, br;q=0", "gzip"));
+        assert!(!StaticFiles::accepts("gzip, br;q=0", "br"));
+        assert!(StaticFiles::accepts("GZIP;q=0.5", "gzip"));
+        assert!(StaticFiles::accepts("*", "br"));
+        assert!(!StaticFiles::accepts("", "gzip"));
+    }
+
+    #[test]
+    fn serves_files() {
+        let root = Root::new("serve");
+        let files = root.files();
+
+        let response = files.serve(&request("digits.txt", &[])).unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers.get("Accept-Ranges"), Some("bytes"));
+        assert_eq!(response.headers.get("Content-Length"), Some("10"));
+        assert!(response.headers.contains("Last-Modified"));
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        assert_eq!(body(response), b"0123456789");
+
+        let response = files
+            .serve(&request("digits.txt", &[("If-None-Match", &etag)]))
+            .unwrap();
+        assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers.get("ETag"), Some(etag.as_str()));
+        assert!(body(response).is_empty());
+
+        let response = files
+            .serve(&request("digits.txt", &[("Range", "bytes=2-4")]))
+            .unwrap();
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.headers.get("Content-Length"), Some("3"));
+        assert_eq!(body(response), b"234");
+
+        let response = files
+            .serve(&request("digits.txt", &[("Range", "bytes=20-")]))
+            .unwrap();
+        assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */10"));
+    }
+
+    #[test]
+    fn serves_precompressed_variants() {
+        let root = Root::new("precompressed");
+        let headers = [("Accept-Encoding", "br, gzip"), ("Range", "bytes=0-0")];
+
+        let response = root.files().serve(&request("app.js", &headers)).unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert!(!response.headers.contains("Accept-Ranges"));
+        assert_eq!(body(response), b"gz");
+
+        let response = root.files().serve(&request("app.js", &[])).unwrap();
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert_eq!(body(response), b"plain");
+
+        let files = root.files().precompressed(false);
+        let response = files.serve(&request("app.js", &headers[..1])).unwrap();
+        assert!(!response.headers.contains("Vary"));
+        assert_eq!(body(response), b"plain");
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_root() {
+        let root = Root::new("outside");
+        let files = root.files();
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.0.join("secret.txt"), root.0.join("root/link")).unwrap();
+
+        for file in [
+            "../secret.txt",
+            "dir/../../secret.txt",
+            "/secret.txt",
+            "dir//digits.txt",
+            "./digits.txt",
+            "link",
+            "dir",
+            "missing.txt",
+        ] {
+            let status = files.serve(&request(file, &[])).err().map(|err| match err {
+                OxidarError::Normal(err) => err.status().as_u16(),
+                _ => 500,
+            });
+            assert_eq!(status, Some(404), "{file}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_variants_outside_the_root() {
+        let root = Root::new("variant");
+        fs::write(root.0.join("root/page.html"), "page").unwrap();
+        std::os::unix::fs::symlink(root.0.join("secret.txt"), root.0.join("root/page.html.br"))
+            .unwrap();
+
+        let headers = [("Accept-Encoding", "br")];
+        let response = root.files().serve(&request("page.html", &headers)).unwrap();
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert_eq!(body(response), b"page");
+    }
+
+    #[test]
+    fn escapes_the_path_in_404_pages() {
+        let root = Root::new("escape");
+        let err = root
+            .files()
+            .serve(&request("<script>.js", &[]))
+            .err()
+            .unwrap();
+
+        match err.to_response().unwrap().content {
+            ResponseContent::Html(html) => {
+                assert!(!html.contains("<script>"));
+                assert!(html.contains("&lt;script&gt;.js"));
+            }
+            _ => panic!("expected an HTML error page"),
+        }
+    }
+}
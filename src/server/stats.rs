@@ -0,0 +1,63 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+#[derive(Debug, Default)]
+struct Counters {
+    workers: AtomicUsize,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    capacity: AtomicUsize,
+}
+
+/// Live counts from the worker pool of a running `Oxidar`, for monitoring.
+#[derive(Debug, Clone, Default)]
+pub struct PoolStats {
+    counters: Arc<Counters>,
+}
+
+impl PoolStats {
+    pub fn new() -> PoolStats {
+        PoolStats::default()
+    }
+
+    /// The number of worker threads.
+    pub fn workers(&self) -> usize {
+        self.counters.workers.load(Ordering::SeqCst)
+    }
+
+    /// The number of workers currently handling a connection.
+    pub fn active_workers(&self) -> usize {
+        self.counters.active.load(Ordering::SeqCst)
+    }
+
+    /// The number of connections waiting for a worker.
+    pub fn queue_depth(&self) -> usize {
+        self.counters.queued.load(Ordering::SeqCst)
+    }
+
+    /// The most connections that may wait for a worker before new ones are
+    /// turned away with a 503 response.
+    pub fn queue_capacity(&self) -> usize {
+        self.counters.capacity.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_pool_size(&self, workers: usize, capacity: usize) {
+        self.counters.workers.store(workers, Ordering::SeqCst);
+        self.counters.capacity.store(capacity, Ordering::SeqCst);
+    }
+
+    pub(crate) fn job_queued(&self) {
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn job_started(&self) {
+        self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+        self.counters.active.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn job_finished(&self) {
+        self.counters.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+#[cfg(feature = "tls")]
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+use super::Oxidar;
+use crate::errors::OxidarError;
+
+/// Turns accepted sockets into `Stream`s, starting a TLS session on each
+/// when TLS is configured.
+#[derive(Clone)]
+pub(crate) struct Acceptor {
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<ServerConfig>>,
+}
+
+impl Acceptor {
+    /// `http2` says whether the server can serve HTTP/2 connections, which
+    /// decides if it can be negotiated through ALPN.
+    pub(crate) fn new(oxidar: &Oxidar, http2: bool) -> Result<Acceptor, OxidarError> {
+        #[cfg(not(feature = "tls"))]
+        let _ = (oxidar, http2);
+
+        Ok(Acceptor {
+            #[cfg(feature = "tls")]
+            tls: match oxidar.tls {
+                Some(ref tls) => Some(tls.server_config(http2)?),
+                None => None,
+            },
+        })
+    }
+
+    pub(crate) fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
+
+    #[cfg(all(feature = "tls", feature = "async"))]
+    pub(crate) fn tls_config(&self) -> Option<Arc<ServerConfig>> {
+        self.tls.clone()
+    }
+
+    /// Wraps the socket. The TLS handshake itself happens on the first read.
+    pub(crate) fn accept(&self, stream: TcpStream) -> Result<Stream, OxidarError> {
+        #[cfg(feature = "tls")]
+        if let Some(ref config) = self.tls {
+            let connection = ServerConnection::new(config.clone()).map_err(|err| {
+                OxidarError::Abortion(crate::errors::Error::Untyped(format!(
+                    "Could not start a TLS session: {err}"
+                )))
+            })?;
+
+            return Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))));
+        }
+
+        Ok(Stream::Plain(stream))
+    }
+}
+
+/// A client connection, either plain TCP or TLS over TCP.
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Gets the underlying socket, for setting timeouts.
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
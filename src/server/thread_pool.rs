@@ -1,9 +1,12 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex, PoisonError},
     thread,
+    time::{Duration, Instant},
 };
 
 use super::Oxidar;
+use crate::errors::OxidarError;
 
 type Job = Box<dyn FnOnce() + Send>;
 
@@ -13,66 +16,134 @@ struct Worker {
 }
 
 impl Worker {
+    /// Starts the worker's thread. The system may refuse to start another
+    /// thread, which results in an error.
     pub(crate) fn new(
         oxidar: Arc<Oxidar>,
         id: usize,
         receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
-    ) -> Self {
-        // todo!()
-        // THIS MIGHT FAIL TO BUILD A THREAD
+    ) -> Result<Self, OxidarError> {
         let noxidar = oxidar.clone();
-        let worker = Self {
-            id,
-            thread: Some(thread::spawn(move || loop {
-                match receiver.lock().unwrap().recv() {
+        let thread = thread::Builder::new()
+            .name(format!("oxidar-worker-{id}"))
+            .spawn(move || loop {
+                // The lock is released before the job runs so a slow or
+                // panicking job can not block or poison the other workers.
+                let job = receiver
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .recv();
+
+                match job {
                     Ok(job) => {
                         noxidar.log(format!("Worker {id} given job."));
-                        job();
+                        noxidar.stats.job_started();
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                            noxidar.loge(format!(
+                                "Worker {id} recovered from a panic: {}",
+                                OxidarError::panic_message(&*payload)
+                            ));
+                        }
+                        noxidar.stats.job_finished();
                     }
                     Err(err) => {
                         noxidar.log(format!("Completed shutdown of worker {id} exiting: {err}."));
                         break;
                     }
                 }
-            })),
+            });
+
+        let worker = Self {
+            id,
+            thread: Some(OxidarError::fio(thread)?),
         };
 
         oxidar.log(format!("Worker {id} created."));
-        return worker;
+        Ok(worker)
     }
 }
 
 pub(crate) struct ThreadPool {
     workers: Vec<Worker>,
-    jobs: Option<mpsc::Sender<Job>>,
+    jobs: Option<mpsc::SyncSender<Job>>,
+    queue_depth: usize,
     oxidar: Arc<Oxidar>,
 }
 
 impl ThreadPool {
-    pub(crate) fn new(oxidar: Arc<Oxidar>, size: usize) -> Self {
-        let (sender, receiver) = mpsc::channel();
+    /// Creates a pool of `size` workers. At most `queue_depth` jobs may wait
+    /// for a worker. Fails if a worker's thread can not be started, in which
+    /// case the workers already started exit.
+    pub(crate) fn new(
+        oxidar: Arc<Oxidar>,
+        size: usize,
+        queue_depth: usize,
+    ) -> Result<Self, OxidarError> {
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
         let receiver = Arc::new(Mutex::new(receiver));
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(oxidar.clone(), id, receiver.clone()));
+            workers.push(Worker::new(oxidar.clone(), id, receiver.clone())?);
         }
 
-        Self {
+        oxidar.stats.set_pool_size(size, queue_depth);
+
+        Ok(Self {
             workers,
             jobs: Some(sender),
+            queue_depth,
             oxidar,
-        }
+        })
+    }
+
+    /// True if the queue is full, so a new job would have to wait for space.
+    pub(crate) fn is_full(&self) -> bool {
+        self.oxidar.stats.queue_depth() >= self.queue_depth
     }
 
+    /// Queues a job. This blocks while the queue is full, so callers that
+    /// must not wait should check `is_full` first.
     pub(crate) fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
+        self.oxidar.stats.job_queued();
         self.jobs.as_ref().unwrap().send(job).unwrap();
     }
+
+    /// Stops taking jobs and waits up to `timeout` for the workers to finish
+    /// the ones already queued. Workers still busy after that are left to
+    /// finish on their own.
+    pub(crate) fn shutdown(&mut self, timeout: Duration) {
+        drop(self.jobs.take());
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline
+            && self
+                .workers
+                .iter()
+                .any(|w| w.thread.as_ref().is_some_and(|t| !t.is_finished()))
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        for worker in self.workers.drain(..) {
+            if let Some(thread) = worker.thread {
+                if !thread.is_finished() {
+                    self.oxidar.logw(format!(
+                        "Worker {} did not finish before the shutdown timeout.",
+                        worker.id
+                    ));
+                } else if thread.join().is_err() {
+                    self.oxidar
+                        .loge(format!("Worker {} exited with a panic.", worker.id));
+                }
+            }
+        }
+    }
 }
 
 impl Drop for ThreadPool {
@@ -83,8 +154,44 @@ impl Drop for ThreadPool {
             self.oxidar
                 .log(format!("Starting shut down of worker {}", worker.id));
             if let Some(thread) = worker.thread {
-                thread.join().unwrap()
+                if thread.join().is_err() {
+                    self.oxidar
+                        .loge(format!("Worker {} exited with a panic.", worker.id));
+                }
             };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::LogStyle;
+
+    #[test]
+    fn workers_survive_panicking_jobs() {
+        let oxidar = Arc::new(Oxidar::new(
+            Vec::new(),
+            "127.0.0.1:0",
+            1,
+            LogStyle::Terminal,
+            false,
+        ));
+        let mut pool = ThreadPool::new(oxidar.clone(), 1, 4).unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        pool.execute(|| panic!("a view panicked"));
+        pool.execute(move || {
+            sender
+                .send(thread::current().name().map(str::to_string))
+                .unwrap()
+        });
+
+        let name = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name.as_deref(), Some("oxidar-worker-0"));
+
+        pool.shutdown(Duration::from_secs(5));
+        assert_eq!(oxidar.stats.active_workers(), 0);
+        assert_eq!(oxidar.stats.queue_depth(), 0);
+    }
+}
@@ -0,0 +1,362 @@
+use std::{collections::HashMap, fmt, path::Path, sync::Arc};
+
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+use crate::errors::OxidarError;
+
+/// Certificates and protocols used to serve requests over TLS, set with
+/// `Oxidar::tls`.
+///
+/// ```ignore
+/// let tls = TlsConfig::from_pem("cert.pem", "key.pem")?
+///     .sni("api.example.com", "api-cert.pem", "api-key.pem")?
+///     .alpn(&["http/1.1"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    resolver: CertResolver,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl TlsConfig {
+    /// Creates a config with no certificates. At least one has to be added
+    /// with `sni` before connections can be accepted.
+    pub fn new() -> TlsConfig {
+        TlsConfig {
+            resolver: CertResolver::default(),
+            alpn: Vec::new(),
+        }
+    }
+
+    /// Creates a config with a certificate chain and private key loaded from
+    /// PEM files. The certificate is used for every server name that does not
+    /// have one of its own.
+    pub fn from_pem<C, K>(cert: C, key: K) -> Result<TlsConfig, OxidarError>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let mut config = TlsConfig::new();
+        config.resolver.default = Some(load_certified_key(cert.as_ref(), key.as_ref())?);
+        Ok(config)
+    }
+
+    /// Adds a certificate chain and private key, loaded from PEM files, that
+    /// is used when the client asks for `server_name` through SNI.
+    pub fn sni<C, K>(mut self, server_name: &str, cert: C, key: K) -> Result<TlsConfig, OxidarError>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let key = load_certified_key(cert.as_ref(), key.as_ref())?;
+        self.resolver
+            .by_name
+            .insert(server_name.to_lowercase(), key);
+        Ok(self)
+    }
+
+    /// Sets the protocols offered through ALPN, most preferred first, such as
+    /// `&["h2", "http/1.1"]`. Clients that offer protocols, but none of these,
+    /// are refused. `h2` is only offered by `Oxidar::run_async` with the
+    /// `http2` feature, as the blocking server speaks HTTP/1.1 alone.
+    pub fn alpn(mut self, protocols: &[&str]) -> TlsConfig {
+        self.alpn = protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        self
+    }
+
+    /// Builds the rustls config. `http2` says whether the server can speak
+    /// HTTP/2; if not, `h2` is left out of the ALPN protocols so it can not be
+    /// negotiated.
+    pub(crate) fn server_config(&self, http2: bool) -> Result<Arc<ServerConfig>, OxidarError> {
+        if self.resolver.default.is_none() && self.resolver.by_name.is_empty() {
+            return Err(OxidarError::abort_std(
+                "The TLS config does not have any certificates.".to_string(),
+            ));
+        }
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| OxidarError::abort_std(format!("Could not set up TLS: {err}")))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(self.resolver.clone()));
+        config.alpn_protocols = self
+            .alpn
+            .iter()
+            .filter(|protocol| http2 || protocol.as_slice() != b"h2")
+            .cloned()
+            .collect();
+
+        Ok(Arc::new(config))
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig::new()
+    }
+}
+
+/// Picks a certificate by the server name the client sent, falling back to
+/// the default certificate.
+#[derive(Default, Clone)]
+struct CertResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("server_names", &self.by_name.keys().collect::<Vec<_>>())
+            .field("default", &self.default.is_some())
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>, OxidarError> {
+    let pem_error = |path: &Path, err: rustls::pki_types::pem::Error| {
+        OxidarError::abort_std(format!("Could not read \"{}\": {err}", path.display()))
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(|err| pem_error(cert, err))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| pem_error(cert, err))?;
+
+    if certs.is_empty() {
+        return Err(OxidarError::abort_std(format!(
+            "\"{}\" does not contain any certificates.",
+            cert.display()
+        )));
+    }
+
+    let der = PrivateKeyDer::from_pem_file(key).map_err(|err| pem_error(key, err))?;
+    let signing_key = any_supported_type(&der).map_err(|err| {
+        OxidarError::abort_std(format!("Unusable private key \"{}\": {err}", key.display()))
+    })?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        path::PathBuf,
+        thread,
+    };
+
+    use rustls::{
+        pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, ServerConnection,
+        StreamOwned,
+    };
+
+    use super::*;
+    use crate::server::{
+        app::{App, AppReg, ViewReg},
+        connect, free_addr, LogStyle, Oxidar,
+    };
+
+    /// A self-signed certificate for `names`, written to PEM files.
+    struct TestCert {
+        cert: CertificateDer<'static>,
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    }
+
+    impl TestCert {
+        fn generate(label: &str, names: &[&str]) -> TestCert {
+            let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            let generated = rcgen::generate_simple_self_signed(names).unwrap();
+
+            let dir = std::env::temp_dir().join(format!("oxidar-tls-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let cert_path = dir.join(format!("{label}-cert.pem"));
+            let key_path = dir.join(format!("{label}-key.pem"));
+            fs::write(&cert_path, generated.cert.pem()).unwrap();
+            fs::write(&key_path, generated.key_pair.serialize_pem()).unwrap();
+
+            TestCert {
+                cert: generated.cert.der().clone(),
+                cert_path,
+                key_path,
+            }
+        }
+    }
+
+    impl Drop for TestCert {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.cert_path);
+            let _ = fs::remove_file(&self.key_path);
+            if let Some(dir) = self.cert_path.parent() {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+    }
+
+    fn client_config(trusted: &[&TestCert], alpn: &[&[u8]]) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in trusted {
+            roots.add(cert.cert.clone()).unwrap();
+        }
+
+        let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Arc::new(config)
+    }
+
+    /// Runs a handshake in memory, returning the client side of it.
+    fn handshake(
+        server: Arc<ServerConfig>,
+        client: Arc<ClientConfig>,
+        name: &str,
+    ) -> Result<ClientConnection, rustls::Error> {
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let mut client = ClientConnection::new(client, name)?;
+        let mut server = ServerConnection::new(server)?;
+
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut sent = Vec::new();
+            client.write_tls(&mut sent).unwrap();
+            let mut sent = sent.as_slice();
+            while !sent.is_empty() {
+                server.read_tls(&mut sent).unwrap();
+            }
+            server.process_new_packets()?;
+
+            let mut sent = Vec::new();
+            server.write_tls(&mut sent).unwrap();
+            let mut sent = sent.as_slice();
+            while !sent.is_empty() {
+                client.read_tls(&mut sent).unwrap();
+            }
+            client.process_new_packets()?;
+        }
+
+        Ok(client)
+    }
+
+    #[test]
+    fn loads_pem_files() {
+        let cert = TestCert::generate("load", &["localhost"]);
+        assert!(TlsConfig::from_pem(&cert.cert_path, &cert.key_path).is_ok());
+
+        // A key where the certificates should be.
+        assert!(TlsConfig::from_pem(&cert.key_path, &cert.key_path).is_err());
+        assert!(TlsConfig::from_pem(&cert.cert_path, &cert.cert_path).is_err());
+        assert!(TlsConfig::from_pem("/nonexistent/cert.pem", &cert.key_path).is_err());
+        assert!(TlsConfig::new().server_config(false).is_err());
+    }
+
+    #[test]
+    fn picks_certificates_by_server_name() {
+        let default = TestCert::generate("default", &["localhost"]);
+        let api = TestCert::generate("api", &["api.test"]);
+
+        let config = TlsConfig::from_pem(&default.cert_path, &default.key_path)
+            .unwrap()
+            .sni("API.test", &api.cert_path, &api.key_path)
+            .unwrap()
+            .server_config(false)
+            .unwrap();
+        let client = client_config(&[&default, &api], &[]);
+
+        let connection = handshake(config.clone(), client.clone(), "localhost").unwrap();
+        assert_eq!(connection.peer_certificates().unwrap()[0], default.cert);
+
+        let connection = handshake(config.clone(), client.clone(), "api.test").unwrap();
+        assert_eq!(connection.peer_certificates().unwrap()[0], api.cert);
+
+        // The default certificate is not valid for other names.
+        assert!(handshake(config, client, "other.test").is_err());
+    }
+
+    #[test]
+    fn only_offers_h2_to_http2_servers() {
+        let cert = TestCert::generate("alpn", &["localhost"]);
+        let tls = TlsConfig::from_pem(&cert.cert_path, &cert.key_path)
+            .unwrap()
+            .alpn(&["h2", "http/1.1"]);
+        let client = client_config(&[&cert], &[b"h2", b"http/1.1"]);
+
+        let blocking = tls.server_config(false).unwrap();
+        assert_eq!(blocking.alpn_protocols, vec![b"http/1.1".to_vec()]);
+        let connection = handshake(blocking, client.clone(), "localhost").unwrap();
+        assert_eq!(connection.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+        let http2 = tls.server_config(true).unwrap();
+        let connection = handshake(http2.clone(), client, "localhost").unwrap();
+        assert_eq!(connection.alpn_protocol(), Some(&b"h2"[..]));
+
+        // Clients offering only protocols the server does not speak are
+        // refused.
+        let client = client_config(&[&cert], &[b"spdy/3"]);
+        assert!(handshake(http2, client, "localhost").is_err());
+    }
+
+    #[test]
+    fn serves_requests_over_tls() {
+        let cert = TestCert::generate("serve", &["localhost"]);
+        let tls = TlsConfig::from_pem(&cert.cert_path, &cert.key_path)
+            .unwrap()
+            .alpn(&["h2", "http/1.1"]);
+
+        let app = App::new(vec![ViewReg::get("/", |_, _| "secure hello")]);
+        let addr = free_addr();
+        let oxidar = Oxidar::new(
+            vec![AppReg::p("/", app)],
+            addr,
+            2,
+            LogStyle::Terminal,
+            false,
+        )
+        .tls(tls);
+        let shutdown = oxidar.shutdown_handle();
+        let server = thread::spawn(move || oxidar.run());
+
+        let socket = connect(addr);
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let client = client_config(&[&cert], &[b"h2", b"http/1.1"]);
+        let connection = ClientConnection::new(client, name).unwrap();
+        let mut stream = StreamOwned::new(connection, socket);
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        let response = String::from_utf8_lossy(&response);
+
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("secure hello"), "{response}");
+
+        shutdown.shutdown();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt::Display;
+
+use super::{app::AppReg, path::PathPattern};
+use crate::{
+    errors::{Error, OxidarError},
+    templates::UrlReverser,
+};
+
+struct NamedUrl {
+    namespace: Option<String>,
+    name: String,
+    prefix: String,
+    path: PathPattern,
+}
+
+/// Every named view registered on an `Oxidar`, used to rebuild urls from
+/// view names.
+pub struct Urls {
+    named: Vec<NamedUrl>,
+}
+
+impl Urls {
+    pub(crate) fn new(apps: &[AppReg]) -> Urls {
+        let mut named = Vec::new();
+
+        for app in apps {
+            let views = app.app.urls().iter().map(|view| (&view.name, &view.path));
+            let sockets = app.app.sockets().iter().map(|s| (&s.name, &s.path));
+
+            for (name, path) in views.chain(sockets) {
+                if let Some(name) = name {
+                    named.push(NamedUrl {
+                        namespace: app.namespace.clone(),
+                        name: name.clone(),
+                        prefix: app.prefix.clone(),
+                        path: path.clone(),
+                    });
+                }
+            }
+        }
+
+        Urls { named }
+    }
+
+    fn find(&self, name: &str) -> Result<&NamedUrl, OxidarError> {
+        let (namespace, name) = match name.split_once(':') {
+            Some((namespace, name)) => (Some(namespace), name),
+            None => (None, name),
+        };
+
+        self.named
+            .iter()
+            .find(|url| url.name == name && url.namespace.as_deref() == namespace)
+            .ok_or(OxidarError::Normal(Error::Untyped(format!(
+                "No view named \"{}\" could be found.",
+                match namespace {
+                    Some(namespace) => format!("{namespace}:{name}"),
+                    None => name.to_string(),
+                }
+            ))))
+    }
+
+    /// Builds the full url of a named view. `name` is either `"view_name"` or
+    /// `"namespace:view_name"`.
+    pub fn reverse<T: Display>(
+        &self,
+        name: &str,
+        args: &[(&str, T)],
+    ) -> Result<String, OxidarError> {
+        let url = self.find(name)?;
+        let args: Vec<(&str, String)> = args
+            .iter()
+            .map(|(arg, value)| (*arg, value.to_string()))
+            .collect();
+
+        match url.path.build(&args) {
+            Ok(path) => Ok(format!("{}{path}", url.prefix)),
+            Err(err) => Err(OxidarError::Normal(Error::Untyped(format!(
+                "Could not reverse \"{name}\": {err}"
+            )))),
+        }
+    }
+
+    /// Like `reverse`, but the arguments fill the view's path parameters in
+    /// the order they appear.
+    pub fn reverse_positional(&self, name: &str, args: &[String]) -> Result<String, OxidarError> {
+        let url = self.find(name)?;
+        let names = url.path.param_names();
+
+        if names.len() != args.len() {
+            return Err(OxidarError::Normal(Error::Untyped(format!(
+                "Could not reverse \"{name}\": expected {} arguments but got {}.",
+                names.len(),
+                args.len()
+            ))));
+        }
+
+        let args: Vec<(&str, &String)> = names.into_iter().zip(args).collect();
+        self.reverse(name, &args)
+    }
+}
+
+impl UrlReverser for Urls {
+    fn reverse_url(&self, name: &str, args: &[String]) -> Result<String, String> {
+        self.reverse_positional(name, args)
+            .map_err(|err| err.to_string())
+    }
+}
@@ -0,0 +1,1008 @@
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+use super::{
+    app::{App, WsReg},
+    http::{Headers, Method, StatusCode, Version},
+    middleware::Next,
+    request::Request,
+    response::{write_header, Response, ResponseContent},
+    shutdown::ShutdownHandle,
+    Oxidar,
+};
+use crate::errors::{Error, OxidarError};
+
+/// Appended to the client's key to work out `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How long to wait for the client to answer a close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Headers of the handshake response that middleware may not set.
+const HANDSHAKE_HEADERS: [&str; 5] = [
+    "upgrade",
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "content-type",
+];
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// A close status code, as defined in RFC 6455 section 7.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    pub const NORMAL: CloseCode = CloseCode(1000);
+    pub const GOING_AWAY: CloseCode = CloseCode(1001);
+    pub const PROTOCOL_ERROR: CloseCode = CloseCode(1002);
+    pub const UNSUPPORTED_DATA: CloseCode = CloseCode(1003);
+    /// Reported when a close frame has no code. Never sent.
+    pub const NO_STATUS: CloseCode = CloseCode(1005);
+    /// Reported when the connection drops without a close frame. Never sent.
+    pub const ABNORMAL: CloseCode = CloseCode(1006);
+    pub const INVALID_DATA: CloseCode = CloseCode(1007);
+    pub const POLICY_VIOLATION: CloseCode = CloseCode(1008);
+    pub const MESSAGE_TOO_BIG: CloseCode = CloseCode(1009);
+    pub const MANDATORY_EXTENSION: CloseCode = CloseCode(1010);
+    pub const INTERNAL_ERROR: CloseCode = CloseCode(1011);
+
+    /// Whether the code may appear in a close frame. 1005 and 1006 only
+    /// describe a close, and codes below 3000 are reserved unless assigned.
+    pub fn is_sendable(&self) -> bool {
+        matches!(self.0, 1000..=1003 | 1007..=1011 | 3000..=4999)
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+/// A message sent or received over a `WebSocket`. Fragmented messages are
+/// put back together before they are returned by `WebSocket::recv`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+/// Anything a `WebSocket` can run over.
+pub(crate) trait Io: Read + Write {}
+
+impl<T: Read + Write> Io for T {}
+
+/// Reads through a `BufReader`, so bytes it already buffered are not lost,
+/// and writes to the stream underneath it.
+pub(crate) struct Buffered<'a, S>(pub &'a mut BufReader<S>);
+
+impl<S: Read> Read for Buffered<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Write> Write for Buffered<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// An open WebSocket connection, handed to the handler of a `WsReg`.
+///
+/// Pings are answered and close frames are replied to automatically, but
+/// both are still returned by `recv` so handlers can see them.
+pub struct WebSocket<'a> {
+    stream: Box<dyn Io + 'a>,
+    buf: Vec<u8>,
+    fragments: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+    protocol: Option<String>,
+    shutdown: ShutdownHandle,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    /// `stream` must time out reads regularly so shutdowns are noticed while
+    /// waiting for a message.
+    pub(crate) fn new(
+        stream: Box<dyn Io + 'a>,
+        socket: &WsReg,
+        protocol: Option<String>,
+        shutdown: ShutdownHandle,
+    ) -> WebSocket<'a> {
+        WebSocket {
+            stream,
+            buf: Vec::new(),
+            fragments: None,
+            max_message_size: socket.max_message_size,
+            protocol,
+            shutdown,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// The subprotocol agreed on during the handshake, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// True once either side has sent a close frame.
+    pub fn is_closed(&self) -> bool {
+        self.close_sent || self.close_received
+    }
+
+    /// Waits for the next message. If the server starts shutting down, the
+    /// connection is closed with `CloseCode::GOING_AWAY` and that close is
+    /// returned.
+    pub fn recv(&mut self) -> Result<Message, OxidarError> {
+        match self.recv_until(None)? {
+            Some(message) => Ok(message),
+            None => unreachable!("Receiving without a deadline never times out."),
+        }
+    }
+
+    /// Waits up to `timeout` for the next message, returning `None` if none
+    /// arrived. Useful for handlers that mostly push updates.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Message>, OxidarError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn send(&mut self, message: Message) -> Result<(), OxidarError> {
+        self.send_fragmented(message, usize::MAX)
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), OxidarError> {
+        self.send(Message::Text(text.to_string()))
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), OxidarError> {
+        self.send(Message::Binary(data.to_vec()))
+    }
+
+    pub fn ping(&mut self, data: &[u8]) -> Result<(), OxidarError> {
+        self.send(Message::Ping(data.to_vec()))
+    }
+
+    /// Sends a message, splitting text and binary messages into frames of at
+    /// most `fragment_size` bytes. Control messages are never fragmented.
+    pub fn send_fragmented(
+        &mut self,
+        message: Message,
+        fragment_size: usize,
+    ) -> Result<(), OxidarError> {
+        if self.close_sent {
+            return Err(closed_error());
+        }
+
+        let (opcode, payload) = match message {
+            Message::Text(text) => (OP_TEXT, text.into_bytes()),
+            Message::Binary(data) => (OP_BINARY, data),
+            Message::Ping(data) => return self.write_control(OP_PING, data),
+            Message::Pong(data) => return self.write_control(OP_PONG, data),
+            Message::Close(frame) => {
+                let frame = frame.unwrap_or(CloseFrame {
+                    code: CloseCode::NORMAL,
+                    reason: String::new(),
+                });
+                return self.close(frame.code, &frame.reason);
+            }
+        };
+
+        let mut chunks = payload.chunks(fragment_size.max(1)).peekable();
+        let mut opcode = opcode;
+
+        if chunks.peek().is_none() {
+            return self.write_frame(true, opcode, &[]);
+        }
+
+        while let Some(chunk) = chunks.next() {
+            self.write_frame(chunks.peek().is_none(), opcode, chunk)?;
+            opcode = OP_CONTINUATION;
+        }
+
+        Ok(())
+    }
+
+    /// Starts the closing handshake and waits briefly for the client's reply.
+    /// Messages that arrive in the meantime are dropped.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), OxidarError> {
+        if self.close_sent {
+            return Ok(());
+        }
+
+        let code = match code.is_sendable() {
+            true => code,
+            false => CloseCode::NORMAL,
+        };
+
+        // Control frame payloads are limited to 125 bytes.
+        let mut reason = reason;
+        while reason.len() > 123 {
+            let mut end = 123;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason = &reason[..end];
+        }
+
+        let mut payload = code.0.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.write_control(OP_CLOSE, payload)?;
+        self.close_sent = true;
+
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while !self.close_received {
+            match self.read_frame(Some(deadline)) {
+                Ok(Some(frame)) if frame.opcode == OP_CLOSE => self.close_received = true,
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<Option<Message>, OxidarError> {
+        if self.close_received {
+            return Err(closed_error());
+        }
+
+        loop {
+            let frame = match self.read_frame(deadline)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            match frame.opcode {
+                OP_PING => {
+                    if !self.close_sent {
+                        self.write_control(OP_PONG, frame.payload.clone())?;
+                    }
+                    return Ok(Some(Message::Ping(frame.payload)));
+                }
+                OP_PONG => return Ok(Some(Message::Pong(frame.payload))),
+                OP_CLOSE => return self.closed_by_client(frame.payload).map(Some),
+                OP_TEXT | OP_BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(self.fail(
+                            CloseCode::PROTOCOL_ERROR,
+                            "A new message started before the last one ended.",
+                        ));
+                    }
+
+                    match frame.fin {
+                        true => return self.message(frame.opcode, frame.payload).map(Some),
+                        false => self.fragments = Some((frame.opcode, frame.payload)),
+                    }
+                }
+                _ => {
+                    let (opcode, mut payload) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => {
+                            return Err(self.fail(
+                                CloseCode::PROTOCOL_ERROR,
+                                "A continuation frame was sent without a message to continue.",
+                            ))
+                        }
+                    };
+
+                    payload.extend_from_slice(&frame.payload);
+                    match frame.fin {
+                        true => return self.message(opcode, payload).map(Some),
+                        false => self.fragments = Some((opcode, payload)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn message(&mut self, opcode: u8, payload: Vec<u8>) -> Result<Message, OxidarError> {
+        match opcode {
+            OP_TEXT => match String::from_utf8(payload) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self.fail(
+                    CloseCode::INVALID_DATA,
+                    "A text message was not valid UTF-8.",
+                )),
+            },
+            _ => Ok(Message::Binary(payload)),
+        }
+    }
+
+    fn closed_by_client(&mut self, payload: Vec<u8>) -> Result<Message, OxidarError> {
+        self.close_received = true;
+
+        let frame = match payload.len() {
+            0 => None,
+            1 => {
+                return Err(self.fail(
+                    CloseCode::PROTOCOL_ERROR,
+                    "A close frame had a one byte payload.",
+                ))
+            }
+            _ => {
+                let code = CloseCode(u16::from_be_bytes([payload[0], payload[1]]));
+                if !code.is_sendable() {
+                    return Err(self.fail(
+                        CloseCode::PROTOCOL_ERROR,
+                        &format!("Invalid close code {code}."),
+                    ));
+                }
+
+                match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => Some(CloseFrame { code, reason }),
+                    Err(_) => {
+                        return Err(self.fail(
+                            CloseCode::INVALID_DATA,
+                            "A close reason was not valid UTF-8.",
+                        ))
+                    }
+                }
+            }
+        };
+
+        if !self.close_sent {
+            let code = frame.as_ref().map_or(CloseCode::NORMAL, |frame| frame.code);
+            self.write_control(OP_CLOSE, code.0.to_be_bytes().to_vec())?;
+            self.close_sent = true;
+        }
+
+        Ok(Message::Close(frame))
+    }
+
+    /// Closes the connection because the client broke the protocol, and
+    /// builds the error reported to the handler.
+    fn fail(&mut self, code: CloseCode, msg: &str) -> OxidarError {
+        if !self.close_sent {
+            let mut payload = code.0.to_be_bytes().to_vec();
+            payload.extend_from_slice(msg.as_bytes().get(..123).unwrap_or(msg.as_bytes()));
+            let _ = self.write_control(OP_CLOSE, payload);
+            self.close_sent = true;
+        }
+        self.close_received = true;
+
+        OxidarError::Abortion(Error::Untyped(format!(
+            "WebSocket closed with {code}: {msg}"
+        )))
+    }
+
+    /// Reads the next whole frame. Returns `None` if `deadline` passes first,
+    /// or if the server starts shutting down while no deadline is set, in
+    /// which case the connection is closed as going away.
+    fn read_frame(&mut self, deadline: Option<Instant>) -> Result<Option<Frame>, OxidarError> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(Some(frame));
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+
+            if self.shutdown.is_shutting_down() && !self.close_sent {
+                self.write_control(OP_CLOSE, CloseCode::GOING_AWAY.0.to_be_bytes().to_vec())?;
+                self.close_sent = true;
+                self.close_received = true;
+                return Ok(Some(Frame {
+                    fin: true,
+                    opcode: OP_CLOSE,
+                    payload: CloseCode::GOING_AWAY.0.to_be_bytes().to_vec(),
+                }));
+            }
+
+            let mut chunk = [0; 8192];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(OxidarError::Abortion(Error::Io(
+                        ErrorKind::UnexpectedEof.into(),
+                    )))
+                }
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(OxidarError::Abortion(Error::Io(err))),
+            }
+        }
+    }
+
+    /// Takes one frame off the front of the read buffer if a whole one has
+    /// arrived.
+    fn parse_frame(&mut self) -> Result<Option<Frame>, OxidarError> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let (first, second) = (self.buf[0], self.buf[1]);
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0F;
+
+        if first & 0x70 != 0 {
+            return Err(self.fail(
+                CloseCode::PROTOCOL_ERROR,
+                "Reserved bits were set without an extension.",
+            ));
+        }
+
+        if !matches!(
+            opcode,
+            OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG
+        ) {
+            return Err(self.fail(
+                CloseCode::PROTOCOL_ERROR,
+                &format!("Unknown opcode {opcode}."),
+            ));
+        }
+
+        if second & 0x80 == 0 {
+            return Err(self.fail(
+                CloseCode::PROTOCOL_ERROR,
+                "Frames from the client must be masked.",
+            ));
+        }
+
+        let (length, mut offset) = match second & 0x7F {
+            126 if self.buf.len() >= 4 => {
+                (u16::from_be_bytes([self.buf[2], self.buf[3]]) as u64, 4)
+            }
+            127 if self.buf.len() >= 10 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&self.buf[2..10]);
+                (u64::from_be_bytes(bytes), 10)
+            }
+            126 | 127 => return Ok(None),
+            length => (length as u64, 2),
+        };
+
+        let is_control = opcode >= OP_CLOSE;
+        if is_control && (!fin || length > 125) {
+            return Err(self.fail(
+                CloseCode::PROTOCOL_ERROR,
+                "Control frames must be whole and at most 125 bytes.",
+            ));
+        }
+
+        let buffered = self.fragments.as_ref().map_or(0, |(_, data)| data.len()) as u64;
+        if length.saturating_add(buffered) > self.max_message_size as u64 {
+            return Err(self.fail(
+                CloseCode::MESSAGE_TOO_BIG,
+                "The message is larger than the server accepts.",
+            ));
+        }
+
+        let length = length as usize;
+        if self.buf.len() < offset + 4 + length {
+            return Ok(None);
+        }
+
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&self.buf[offset..offset + 4]);
+        offset += 4;
+
+        let mut payload = self.buf[offset..offset + length].to_vec();
+        for (idx, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[idx % 4];
+        }
+
+        self.buf.drain(..offset + length);
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+
+    fn write_control(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), OxidarError> {
+        if payload.len() > 125 {
+            return Err(OxidarError::Normal(Error::Untyped(
+                "Control frame payloads can be at most 125 bytes.".to_string(),
+            )));
+        }
+
+        self.write_frame(true, opcode, &payload)
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> Result<(), OxidarError> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(if fin { 0x80 } else { 0 } | opcode);
+
+        match payload.len() {
+            length @ 0..=125 => frame.push(length as u8),
+            length @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(payload);
+        OxidarError::aio(self.stream.write_all(&frame))?;
+        OxidarError::aio(self.stream.flush())
+    }
+}
+
+fn closed_error() -> OxidarError {
+    OxidarError::Abortion(Error::Untyped("The WebSocket is closed.".to_string()))
+}
+
+/// True if the request asks to be upgraded to a WebSocket.
+pub(crate) fn is_upgrade(request: &Request) -> bool {
+    request
+        .header("Upgrade")
+        .is_some_and(|upgrade| has_token(upgrade, "websocket"))
+}
+
+fn has_token(header: &str, token: &str) -> bool {
+    header
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Checks an upgrade request against RFC 6455 section 4.2.1 and builds the
+/// raw `101 Switching Protocols` response, along with the chosen subprotocol.
+pub(crate) fn handshake(
+    request: &Request,
+    socket: &WsReg,
+    headers: &Headers,
+) -> Result<(String, Option<String>), Response> {
+    let bad_request = |msg: &str| {
+        OxidarError::http_400(Some(msg.to_string()))
+            .to_response()
+            .expect("Http errors always have a response.")
+    };
+
+    if request.method != Method::GET || request.version != Version::Http1_1 {
+        return Err(bad_request(
+            "WebSocket upgrades must be HTTP/1.1 GET requests.",
+        ));
+    }
+
+    if !request
+        .header("Connection")
+        .is_some_and(|connection| has_token(connection, "upgrade"))
+    {
+        return Err(bad_request(
+            "WebSocket upgrades must send \"Connection: Upgrade\".",
+        ));
+    }
+
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        let response = OxidarError::http(
+            StatusCode::UPGRADE_REQUIRED,
+            Some("Only WebSocket version 13 is supported.".to_string()),
+        )
+        .to_response()
+        .expect("Http errors always have a response.");
+
+        return Err(response.header("Sec-WebSocket-Version", "13"));
+    }
+
+    let key = request
+        .header("Sec-WebSocket-Key")
+        .unwrap_or_default()
+        .trim();
+    if STANDARD.decode(key).map(|key| key.len()) != Ok(16) {
+        return Err(bad_request("Invalid Sec-WebSocket-Key."));
+    }
+
+    let accept = STANDARD.encode(Sha1::digest(format!("{key}{ACCEPT_GUID}").as_bytes()));
+
+    let offered = request
+        .headers
+        .get_all("Sec-WebSocket-Protocol")
+        .into_iter()
+        .flat_map(|protocols| protocols.split(','))
+        .map(|protocol| protocol.trim().to_string())
+        .collect::<Vec<String>>();
+    let protocol = socket
+        .protocols
+        .iter()
+        .find(|protocol| offered.contains(protocol))
+        .cloned();
+
+    let mut response = format!(
+        "{} {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n",
+        Version::Http1_1,
+        StatusCode::SWITCHING_PROTOCOLS
+    );
+    if let Some(ref protocol) = protocol {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {protocol}\r\n"));
+    }
+
+    // Headers added by middleware are sent too, except ones that would change
+    // the handshake or the framing of the connection.
+    for (name, value) in headers.iter() {
+        let name_lower = name.to_ascii_lowercase();
+        if !HANDSHAKE_HEADERS.contains(&name_lower.as_str())
+            && !name_lower.starts_with("sec-websocket-")
+        {
+            write_header(&mut response, name, value);
+        }
+    }
+    response.push_str("\r\n");
+
+    Ok((response, protocol))
+}
+
+/// What the middleware made of a WebSocket upgrade request.
+pub(crate) enum Upgrade<'a> {
+    /// The request reached a WebSocket handler. The headers are those the
+    /// middleware added to the response.
+    Accept(&'a App, &'a WsReg, Box<Request>, Headers),
+    /// The middleware answered the request itself, failed, or routed it
+    /// somewhere other than a WebSocket handler.
+    Respond(Result<Response, OxidarError>),
+}
+
+impl Oxidar {
+    /// Runs an upgrade request through the global and app middleware, as
+    /// `respond` does, so middleware can turn it away before the handshake.
+    /// If the request leads to a WebSocket handler once the middleware is done
+    /// with it, it is handed back to be upgraded. Otherwise it goes to the
+    /// app's views like any other request.
+    pub(crate) fn upgrade_websocket(&self, request: Request) -> Upgrade<'_> {
+        let accepted = RefCell::new(None);
+
+        let route = |request: Request| match self.find_app(&request.path) {
+            Some((app, path)) => {
+                let endpoint = |mut request: Request| match app.app.websocket(&path, &mut request) {
+                    Some(socket) => {
+                        *accepted.borrow_mut() = Some((&app.app, socket, request));
+                        Ok(Response::new(ResponseContent::Empty)
+                            .status(StatusCode::SWITCHING_PROTOCOLS))
+                    }
+                    None => app.app.respond(self, &path, request),
+                };
+                Next::new(&app.middleware, &endpoint).run(request)
+            }
+            None => Self::no_app(),
+        };
+
+        let (method, uri) = (request.method, request.uri.clone());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            Next::new(&self.middleware, &route).run(request)
+        }));
+
+        match (result, accepted.into_inner()) {
+            (Ok(Ok(response)), Some((app, socket, request)))
+                if response.status == StatusCode::SWITCHING_PROTOCOLS =>
+            {
+                Upgrade::Accept(app, socket, Box::new(request), response.headers)
+            }
+            (Ok(result), _) => Upgrade::Respond(result),
+            (Err(payload), _) => Upgrade::Respond(Err(self.view_panicked(method, &uri, &*payload))),
+        }
+    }
+
+    /// Completes the upgrade and runs the handler until it returns, then
+    /// closes the socket. The connection can not be reused afterwards.
+    pub(crate) fn serve_websocket(
+        &self,
+        app: &App,
+        socket: &WsReg,
+        request: Request,
+        headers: Headers,
+        mut stream: Box<dyn Io + '_>,
+    ) -> Result<(), OxidarError> {
+        self.log(format!("Upgrading: {} {}", request.method, request.uri));
+
+        let protocol = match handshake(&request, socket, &headers) {
+            Ok((head, protocol)) => {
+                OxidarError::aio(stream.write_all(head.as_bytes()))?;
+                OxidarError::aio(stream.flush())?;
+                protocol
+            }
+            Err(response) => {
+                let response = response.header("Connection", "close");
+                OxidarError::aio(stream.write_all(&response.build_response()))?;
+                return OxidarError::aio(stream.flush());
+            }
+        };
+
+        let mut websocket = WebSocket::new(stream, socket, protocol, self.shutdown.clone());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            (socket.handler)(app, &request, &mut websocket)
+        }));
+
+        let code = match result {
+            Ok(Ok(())) => CloseCode::NORMAL,
+            _ => CloseCode::INTERNAL_ERROR,
+        };
+        if !websocket.is_closed() {
+            let _ = websocket.close(code, "");
+        }
+
+        match result {
+            Ok(result) => result,
+            Err(payload) => Err(self.view_panicked(request.method, &request.uri, &*payload)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::request::test_request;
+
+    /// A connection that reads from `input` and writes to `output`.
+    struct Pipe<'a> {
+        input: &'a [u8],
+        output: &'a mut Vec<u8>,
+    }
+
+    impl Read for Pipe<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn socket() -> WsReg {
+        WsReg::p("/ws", |_, _, _| Ok(()))
+            .protocols(&["chat", "superchat"])
+            .max_message_size(100_000)
+    }
+
+    fn websocket<'a>(input: &'a [u8], output: &'a mut Vec<u8>) -> WebSocket<'a> {
+        let stream = Box::new(Pipe { input, output });
+        WebSocket::new(stream, &socket(), None, ShutdownHandle::new())
+    }
+
+    /// A frame as a client sends it, masked.
+    fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first];
+
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length @ 126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(idx, b)| b ^ mask[idx % 4]));
+        frame
+    }
+
+    /// The code of the last frame the server sent, which must be a close
+    /// frame. Frames the server sends here are all short.
+    fn close_code(output: &[u8]) -> u16 {
+        let mut last = 0;
+        let mut idx = 0;
+        while idx < output.len() {
+            last = idx;
+            idx += 2 + output[idx + 1] as usize;
+        }
+
+        assert_eq!(output[last], 0x88);
+        u16::from_be_bytes([output[last + 2], output[last + 3]])
+    }
+
+    fn upgrade_request(headers: &[(&str, &str)]) -> Request {
+        let mut all = vec![
+            ("Host", "example.com"),
+            ("Upgrade", "websocket"),
+            ("Connection", "keep-alive, Upgrade"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("Sec-WebSocket-Version", "13"),
+        ];
+        all.retain(|(name, _)| !headers.iter().any(|(other, _)| other == name));
+        all.extend_from_slice(headers);
+
+        test_request(Method::GET, "/ws", &all, b"")
+    }
+
+    #[test]
+    fn answers_handshakes() {
+        let mut headers = Headers::new();
+        headers.insert("X-Custom", "kept");
+        headers.insert("Content-Length", "0");
+        headers.insert("Sec-WebSocket-Accept", "forged");
+
+        let request = upgrade_request(&[("Sec-WebSocket-Protocol", "superchat, chat")]);
+        let Ok((response, protocol)) = handshake(&request, &socket(), &headers) else {
+            panic!("The handshake was refused.");
+        };
+
+        // The example from RFC 6455 section 1.3.
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert_eq!(protocol.as_deref(), Some("chat"));
+        assert!(response.contains("X-Custom: kept\r\n"));
+        assert!(!response.contains("forged"));
+        assert!(!response.contains("Content-Length"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn refuses_bad_handshakes() {
+        let headers = Headers::new();
+        let status = |request: Request| {
+            handshake(&request, &socket(), &headers)
+                .unwrap_err()
+                .status
+                .as_u16()
+        };
+
+        assert_eq!(
+            status(upgrade_request(&[("Sec-WebSocket-Version", "8")])),
+            426
+        );
+        assert_eq!(
+            status(upgrade_request(&[("Sec-WebSocket-Key", "c2hvcnQ=")])),
+            400
+        );
+        assert_eq!(
+            status(upgrade_request(&[("Connection", "keep-alive")])),
+            400
+        );
+
+        let mut request = upgrade_request(&[]);
+        request.method = Method::POST;
+        assert_eq!(status(request), 400);
+
+        let request = upgrade_request(&[("Sec-WebSocket-Protocol", "mqtt")]);
+        let protocol = handshake(&request, &socket(), &headers).map(|(_, protocol)| protocol);
+        assert!(matches!(protocol, Ok(None)));
+    }
+
+    #[test]
+    fn reads_messages() {
+        let mut input = frame(0x81, b"Hello");
+        input.extend(frame(0x82, &[0, 1, 2]));
+        input.extend(frame(0x81, &"a".repeat(300).into_bytes()));
+        input.extend(frame(0x82, &vec![7; 70_000]));
+
+        let mut output = Vec::new();
+        let mut ws = websocket(&input, &mut output);
+
+        assert_eq!(ws.recv().unwrap(), Message::Text("Hello".to_string()));
+        assert_eq!(ws.recv().unwrap(), Message::Binary(vec![0, 1, 2]));
+        assert_eq!(ws.recv().unwrap(), Message::Text("a".repeat(300)));
+        assert_eq!(ws.recv().unwrap(), Message::Binary(vec![7; 70_000]));
+    }
+
+    #[test]
+    fn joins_fragments_and_answers_pings_between_them() {
+        let mut input = frame(0x01, b"Hel");
+        input.extend(frame(0x89, b"ping"));
+        input.extend(frame(0x00, b"l"));
+        input.extend(frame(0x80, b"o"));
+
+        let mut output = Vec::new();
+        let mut ws = websocket(&input, &mut output);
+
+        assert_eq!(ws.recv().unwrap(), Message::Ping(b"ping".to_vec()));
+        assert_eq!(ws.recv().unwrap(), Message::Text("Hello".to_string()));
+        drop(ws);
+
+        assert_eq!(output, b"\x8a\x04ping");
+    }
+
+    #[test]
+    fn writes_frames() {
+        let mut output = Vec::new();
+        let mut ws = websocket(&[], &mut output);
+
+        ws.send_text("hi").unwrap();
+        ws.send_binary(&[1; 300]).unwrap();
+        ws.send_binary(&vec![2; 70_000]).unwrap();
+        ws.send_fragmented(Message::Text("abcde".to_string()), 2)
+            .unwrap();
+        drop(ws);
+
+        let mut expected = b"\x81\x02hi\x82\x7e\x01\x2c".to_vec();
+        expected.extend_from_slice(&[1; 300]);
+        expected.extend_from_slice(b"\x82\x7f\x00\x00\x00\x00\x00\x01\x11\x70");
+        expected.extend_from_slice(&vec![2; 70_000]);
+        expected.extend_from_slice(b"\x01\x02ab\x00\x02cd\x80\x01e");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn replies_to_close_frames() {
+        let mut payload = 1000u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        let input = frame(0x88, &payload);
+
+        let mut output = Vec::new();
+        let mut ws = websocket(&input, &mut output);
+
+        let close = CloseFrame {
+            code: CloseCode::NORMAL,
+            reason: "bye".to_string(),
+        };
+        assert_eq!(ws.recv().unwrap(), Message::Close(Some(close)));
+        assert!(ws.is_closed());
+        assert!(ws.recv().is_err());
+        drop(ws);
+
+        assert_eq!(output, b"\x88\x02\x03\xe8");
+    }
+
+    #[test]
+    fn fails_on_protocol_errors() {
+        let mut too_big = frame(0x02, &[0; 60_000]);
+        too_big.extend(frame(0x80, &[0; 60_000]));
+
+        // Unmasked, reserved bits, unknown opcode, fragmented control frame,
+        // long control frame, stray continuation, interleaved messages,
+        // invalid UTF-8, too big, and a close code that can not be sent.
+        let cases: [(Vec<u8>, u16); 10] = [
+            (b"\x81\x01a".to_vec(), 1002),
+            (frame(0xC1, b"a"), 1002),
+            (frame(0x83, b"a"), 1002),
+            (frame(0x09, b"a"), 1002),
+            (frame(0x89, &[0; 126]), 1002),
+            (frame(0x80, b"a"), 1002),
+            ([frame(0x01, b"a"), frame(0x81, b"b")].concat(), 1002),
+            (frame(0x81, b"\xff\xfe"), 1007),
+            (too_big, 1009),
+            (frame(0x88, &1005u16.to_be_bytes()), 1002),
+        ];
+
+        for (input, code) in cases {
+            let mut output = Vec::new();
+            let mut ws = websocket(&input, &mut output);
+
+            assert!(ws.recv().is_err(), "{input:?}");
+            assert!(ws.is_closed());
+            drop(ws);
+            assert_eq!(close_code(&output), code, "{input:?}");
+        }
+    }
+}
@@ -4,7 +4,7 @@ mod var;
 use std::collections::HashMap;
 
 pub use oxidar_derive::ToTemplateVar;
-pub use parser::TemplateParsingError;
+pub use parser::{TemplateParsingError, UrlReverser};
 pub use var::{TemplateVar, ToTemplateVar};
 
 pub fn resolve_template_string(
@@ -12,5 +12,16 @@ pub fn resolve_template_string(
     data: HashMap<&'static str, TemplateVar>,
 ) -> Result<String, TemplateParsingError> {
     let temp_var = TemplateVar::Indexable(data);
-    return parser::parse(initial, &temp_var);
+    parser::parse(initial, &temp_var, None)
+}
+
+/// Resolves a template string that may use `{ url "namespace:name" arg }`
+/// blocks, rebuilding each url with `urls`.
+pub fn resolve_template_string_with_urls(
+    initial: String,
+    data: HashMap<&'static str, TemplateVar>,
+    urls: &dyn UrlReverser,
+) -> Result<String, TemplateParsingError> {
+    let temp_var = TemplateVar::Indexable(data);
+    parser::parse(initial, &temp_var, Some(urls))
 }
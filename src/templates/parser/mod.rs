@@ -3,6 +3,12 @@ mod error;
 use super::TemplateVar;
 pub use error::TemplateParsingError;
 
+/// Rebuilds urls for `{ url "namespace:name" arg }` template blocks. The
+/// arguments fill the view's path parameters in order.
+pub trait UrlReverser {
+    fn reverse_url(&self, name: &str, args: &[String]) -> Result<String, String>;
+}
+
 pub struct CharStream {
     idx: usize,
     chars: Vec<char>,
@@ -21,6 +27,22 @@ impl CharStream {
         self.idx += 1;
         return c;
     }
+
+    /// Whether the token just read is followed by a string in the same
+    /// block, looking ahead without moving.
+    fn string_follows(&self) -> bool {
+        if self.chars.get(self.idx.saturating_sub(1)) == Some(&'}') {
+            return false;
+        }
+
+        match self.chars[self.idx.min(self.chars.len())..]
+            .iter()
+            .find(|c| !c.is_whitespace())
+        {
+            Some(c) => *c == '"' || *c == '\'',
+            None => false,
+        }
+    }
 }
 
 struct TokenStream<'a> {
@@ -74,6 +96,7 @@ enum TemplateToken<'a> {
     Set,
     And,
     Or,
+    Url,
     Value(TemplateVar),
     ValueRef(&'a TemplateVar),
     Html(String),
@@ -102,6 +125,9 @@ fn finalize_block_token<'a>(
         "in" => (true, TemplateToken::In),
         "block" => (true, TemplateToken::Block),
         "let" => (false, TemplateToken::Let),
+        // `url` only starts a url block when a view name follows, so it can
+        // still be used as a variable name.
+        "url" if char_stream.string_follows() => (true, TemplateToken::Url),
         "safe" => (true, TemplateToken::Let),
         "+" => (false, TemplateToken::Add),
         "-" => (false, TemplateToken::Subtract),
@@ -330,10 +356,64 @@ fn resolve_value_led_block(
     return Ok(chars);
 }
 
+fn resolve_url_block(
+    idx: usize,
+    token_stream: &mut TokenStream,
+    output: &mut String,
+    chars: Vec<char>,
+    urls: Option<&dyn UrlReverser>,
+) -> Result<Vec<char>, TemplateParsingError> {
+    let urls = match urls {
+        Some(urls) => urls,
+        None => {
+            return Err(TemplateParsingError::err(
+                "Url blocks can not be used without a url reverser.".to_string(),
+                idx,
+                chars,
+            ))
+        }
+    };
+
+    let name = match token_stream.get() {
+        Some((TemplateToken::Value(TemplateVar::Str(name)), _)) => name.clone(),
+        _ => {
+            return Err(TemplateParsingError::err(
+                "A url block must start with the name of a view as a string.".to_string(),
+                idx,
+                chars,
+            ))
+        }
+    };
+
+    let mut args = Vec::new();
+    while !token_stream.next_is_html() {
+        match token_stream.get() {
+            Some((TemplateToken::Value(value), _)) => args.push(value.string()),
+            Some((TemplateToken::ValueRef(value), _)) => args.push(value.string()),
+            Some((token, idx)) => {
+                return Err(TemplateParsingError::err(
+                    format!("Invalid url argument \"{:?}\".", token),
+                    *idx,
+                    chars,
+                ))
+            }
+            None => break,
+        }
+    }
+
+    match urls.reverse_url(&name, &args) {
+        Ok(url) => output.push_str(&url),
+        Err(err) => return Err(TemplateParsingError::err(err, idx, chars)),
+    }
+
+    Ok(chars)
+}
+
 fn resolve_block(
     token_stream: &mut TokenStream,
     output: &mut String,
     mut chars: Vec<char>,
+    urls: Option<&dyn UrlReverser>,
 ) -> Result<Vec<char>, TemplateParsingError> {
     if let Some((token, idx)) = token_stream.get() {
         match token {
@@ -344,6 +424,10 @@ fn resolve_block(
             TemplateToken::In => todo!(),
             TemplateToken::Block => todo!(),
             TemplateToken::Not => todo!(),
+            TemplateToken::Url => {
+                let idx = *idx;
+                chars = resolve_url_block(idx, token_stream, output, chars, urls)?;
+            }
             TemplateToken::Value(template_var) => {
                 chars = resolve_value_led_block(
                     template_var.clone(),
@@ -375,15 +459,60 @@ fn resolve_block(
     return Ok(chars);
 }
 
-pub fn parse(initial: String, data: &TemplateVar) -> Result<String, TemplateParsingError> {
+pub fn parse(
+    initial: String,
+    data: &TemplateVar,
+    urls: Option<&dyn UrlReverser>,
+) -> Result<String, TemplateParsingError> {
     let (token_list, mut chars) = lex(initial, data)?;
     let mut token_stream = TokenStream::new(token_list);
 
     let mut output = String::new();
     while let Some((TemplateToken::Html(html), _idx)) = token_stream.get() {
         output.push_str(html);
-        chars = resolve_block(&mut token_stream, &mut output, chars)?;
+        chars = resolve_block(&mut token_stream, &mut output, chars, urls)?;
     }
 
     return Ok(output);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Urls;
+
+    impl UrlReverser for Urls {
+        fn reverse_url(&self, name: &str, args: &[String]) -> Result<String, String> {
+            Ok(format!("/{name}/{}", args.join("/")))
+        }
+    }
+
+    fn render(template: &str) -> Result<String, TemplateParsingError> {
+        let data = TemplateVar::Indexable(HashMap::from([
+            ("url", TemplateVar::Str("https://example.com".to_string())),
+            ("id", TemplateVar::Num(5.0)),
+        ]));
+        parse(template.to_string(), &data, Some(&Urls))
+    }
+
+    #[test]
+    fn url_blocks_reverse_views() {
+        assert_eq!(
+            render("<a href=\"{ url \"post\" id }\">").unwrap(),
+            "<a href=\"/post/5\">"
+        );
+        assert_eq!(render("{url 'post' 7}").unwrap(), "/post/7");
+    }
+
+    #[test]
+    fn url_is_still_a_variable_name() {
+        assert_eq!(render("{ url }").unwrap(), "https://example.com");
+        assert_eq!(render("{url}").unwrap(), "https://example.com");
+        assert_eq!(
+            render("{ url }\"quoted\"").unwrap(),
+            "https://example.com\"quoted\""
+        );
+    }
+}